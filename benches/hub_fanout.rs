@@ -0,0 +1,103 @@
+//! Cost of broadcasting one message to a 10-socket chat as the number of
+//! unrelated chats on the server grows, for the per-chat `Hub` and for the
+//! single socket map it replaced (scan everything, filter by chat).
+//!
+//! Run with `cargo bench --bench hub_fanout`.
+
+#![allow(dead_code)]
+
+#[path = "../src/protocol.rs"]
+mod protocol;
+#[path = "../src/hub.rs"]
+mod hub;
+#[path = "../src/outbox.rs"]
+mod outbox;
+
+use axum::extract::ws::Message;
+use hub::{Hub, SocketData};
+use outbox::{Outbox, OutboxStats, OverflowPolicy};
+use protocol::{ServerEvent, CURRENT_VERSION};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const SOCKETS_PER_CHAT: usize = 10;
+const BROADCASTS: u32 = 2_000;
+const TARGET_CHAT: i64 = 0;
+
+// Nobody drains the outboxes; a one-message drop_oldest queue keeps them from growing
+fn socket(chat_id: i64, n: usize, stats: &Arc<OutboxStats>) -> SocketData {
+    SocketData {
+        chat_id,
+        user_id: n as i64,
+        username: format!("user{}", n),
+        session_id: n as i64,
+        version: CURRENT_VERSION,
+        socket: Arc::new(Outbox::new(1, OverflowPolicy::DropOldest, stats.clone())),
+    }
+}
+
+fn event() -> ServerEvent {
+    ServerEvent::Message {
+        id: 1,
+        chat_id: TARGET_CHAT,
+        author: "user0".to_string(),
+        text: "hello".to_string(),
+        sent_at: "2024-01-01T00:00:00Z".to_string(),
+    }
+}
+
+/// Average time per broadcast.
+fn time_broadcasts(mut broadcast: impl FnMut()) -> Duration {
+    let mut total = Duration::ZERO;
+    for _ in 0..BROADCASTS {
+        let start = Instant::now();
+        broadcast();
+        total += start.elapsed();
+    }
+    total / BROADCASTS
+}
+
+fn bench_hub(unrelated_chats: i64) -> Duration {
+    let hub = Hub::default();
+    let stats = Arc::new(OutboxStats::default());
+    for chat_id in 0..=unrelated_chats {
+        for n in 0..SOCKETS_PER_CHAT {
+            hub.join(&format!("{}-{}", chat_id, n), socket(chat_id, n, &stats));
+        }
+    }
+    let event = event();
+    time_broadcasts(|| hub.broadcast(TARGET_CHAT, None, &event))
+}
+
+fn bench_global_map(unrelated_chats: i64) -> Duration {
+    let mut sockets = HashMap::new();
+    let stats = Arc::new(OutboxStats::default());
+    for chat_id in 0..=unrelated_chats {
+        for n in 0..SOCKETS_PER_CHAT {
+            sockets.insert(format!("{}-{}", chat_id, n), socket(chat_id, n, &stats));
+        }
+    }
+    let sockets = std::sync::Mutex::new(sockets);
+    let event = event();
+    time_broadcasts(|| {
+        let sockets = sockets.lock().unwrap();
+        for socket_data in sockets.values() {
+            if socket_data.chat_id != TARGET_CHAT {
+                continue;
+            }
+            if let Some(frame) = event.encode(socket_data.version) {
+                socket_data.socket.push(Message::Text(frame));
+            }
+        }
+    })
+}
+
+fn main() {
+    println!("{:>15} {:>15} {:>15}", "unrelated chats", "hub", "global map");
+    for unrelated_chats in [0, 100, 1_000, 10_000] {
+        let hub = bench_hub(unrelated_chats);
+        let global = bench_global_map(unrelated_chats);
+        println!("{:>15} {:>15?} {:>15?}", unrelated_chats, hub, global);
+    }
+}
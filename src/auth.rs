@@ -1,15 +1,29 @@
 use axum::{
     async_trait,
-    extract::FromRequestParts,
-    http::request::Parts,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap, HeaderValue, Method, StatusCode},
     response::{IntoResponse, Redirect, Response},
 };
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use sha2::{Digest, Sha256};
+use std::convert::Infallible;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use unicode_normalization::UnicodeNormalization;
 use crate::AppState;
 
+/// Header carrying the session's CSRF token on state-changing requests.
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
 #[allow(dead_code)]
 pub struct AuthenticatedUser {
     pub user_id: i64,
     pub username: String,
+    pub session_id: i64,
+    // Rendered into pages for their scripts to send back in `CSRF_HEADER`
+    pub csrf_token: String,
 }
 
 #[async_trait]
@@ -17,19 +31,292 @@ impl FromRequestParts<AppState> for AuthenticatedUser {
     type Rejection = Response;
 
     async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
-        // Extract session_token from Cookie header
-        let token = parts
-            .headers
-            .get("cookie")
-            .and_then(|h| h.to_str().ok())
-            .and_then(|s| s.split(';').find(|c| c.trim_start().starts_with("session_token=")))
-            .map(|c| c.trim_start()[14..].to_string());
-
-        if let Some(token) = token {
-            if let Ok(Some((user_id, username))) = state.db_action().validate_session(&token) {
-                return Ok(AuthenticatedUser { user_id, username });
+        if let Some(token) = session_token(&parts.headers) {
+            let sliding_ttl_hours = state.config.session_sliding_expiry.then_some(state.config.session_ttl_hours);
+            if let Ok(Some(session)) = state.db_action().validate_session(&token, sliding_ttl_hours).await {
+                // The cookie alone proves nothing for a state-changing request,
+                // another site can make the browser send it
+                if !is_safe_method(&parts.method) && !has_csrf_token(&parts.headers, &session.csrf_token) {
+                    return Err((StatusCode::FORBIDDEN, "Missing or invalid CSRF token").into_response());
+                }
+                return Ok(AuthenticatedUser {
+                    user_id: session.user_id,
+                    username: session.username,
+                    session_id: session.session_id,
+                    csrf_token: session.csrf_token,
+                });
             }
         }
         Err(Redirect::to("/auth").into_response())
     }
-}
\ No newline at end of file
+}
+
+/// The session_token cookie of a request, if any.
+pub fn session_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("cookie")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.split(';').find_map(|c| c.trim_start().strip_prefix("session_token=")))
+        .map(|token| token.to_string())
+}
+
+fn is_safe_method(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+fn has_csrf_token(headers: &HeaderMap, csrf_token: &str) -> bool {
+    headers
+        .get(CSRF_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|sent| constant_time_eq(sent.as_bytes(), csrf_token.as_bytes()))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// `Set-Cookie` value for the session cookie. `None` clears it.
+pub fn session_cookie(state: &AppState, token: Option<&str>) -> HeaderValue {
+    let same_site = match state.config.cookie_same_site {
+        SameSite::Strict => "Strict",
+        SameSite::Lax => "Lax",
+        SameSite::None => "None",
+    };
+    let mut cookie = match token {
+        Some(token) => format!("session_token={}; HttpOnly; Path=/; SameSite={}", token, same_site),
+        None => format!("session_token=; HttpOnly; Path=/; SameSite={}; Max-Age=0", same_site),
+    };
+    if state.config.cookie_secure {
+        cookie.push_str("; Secure");
+    }
+    HeaderValue::from_str(&cookie).unwrap()
+}
+
+/// Whether a request comes from one of our own pages. Used where there is no
+/// session CSRF token to check: WebSocket upgrades, and logins and sign-ups,
+/// which another site could otherwise use to log the browser into its own
+/// account. Browsers always send `Origin` with WebSocket handshakes and POSTs and
+/// pages can't forge it; clients without one aren't browsers, so the request
+/// wasn't made on anyone's behalf.
+pub fn is_allowed_origin(headers: &HeaderMap, state: &AppState) -> bool {
+    let Some(origin) = headers.get("origin").and_then(|h| h.to_str().ok()) else {
+        return true;
+    };
+    if state.config.allowed_origins.iter().any(|allowed| allowed == origin) {
+        return true;
+    }
+    // Otherwise only the origin this server is reached at
+    let host = headers.get("host").and_then(|h| h.to_str().ok());
+    let origin_host = origin.split_once("://").map(|(_, host)| host);
+    host.is_some() && origin_host == host
+}
+
+/// The address a request came from: the TCP peer, or with `trust_forwarded_for`
+/// the last `X-Forwarded-For` entry, which is the one our reverse proxy added.
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        if state.config.trust_forwarded_for {
+            let forwarded = parts
+                .headers
+                .get("x-forwarded-for")
+                .and_then(|h| h.to_str().ok())
+                .and_then(|s| s.rsplit(',').next())
+                .and_then(|ip| ip.trim().parse().ok());
+            if let Some(ip) = forwarded {
+                return Ok(ClientIp(ip));
+            }
+        }
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        Ok(ClientIp(peer))
+    }
+}
+
+/// Hashes a password with Argon2id, returning a PHC-format string.
+/// Runs on the blocking pool since Argon2 is deliberately slow.
+pub async fn hash_password(password: String) -> Result<String, String> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+pub enum PasswordCheck {
+    Valid,
+    // Password matched a legacy SHA-256 hash and should be rehashed
+    ValidLegacy,
+    Invalid,
+}
+
+/// Verifies a password against a stored hash, accepting both Argon2 PHC strings
+/// and the legacy unsalted SHA-256 hex digests.
+pub async fn verify_password(password: String, stored_hash: String) -> PasswordCheck {
+    tokio::task::spawn_blocking(move || {
+        if let Ok(parsed) = PasswordHash::new(&stored_hash) {
+            return match Argon2::default().verify_password(password.as_bytes(), &parsed) {
+                Ok(_) => PasswordCheck::Valid,
+                Err(_) => PasswordCheck::Invalid,
+            };
+        }
+        let legacy = format!("{:x}", Sha256::digest(password.as_bytes()));
+        if constant_time_eq(legacy.as_bytes(), stored_hash.as_bytes()) {
+            PasswordCheck::ValidLegacy
+        } else {
+            PasswordCheck::Invalid
+        }
+    })
+    .await
+    .unwrap_or(PasswordCheck::Invalid)
+}
+
+/// Argon2 hash, with `Argon2::default()` parameters, of a password nobody uses.
+/// Checked when a login names an unknown user, so it takes as long as a real one.
+const DUMMY_PASSWORD_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$0JcCZ/OqTgwwcOfdvcJCng$NVFAeoxBp1xFrBknKAZhxjiCrGAkxtsQZ6OlmuWb7IA";
+
+/// Spends the time of a `verify_password` on a login for a username that
+/// doesn't exist, so response times don't reveal which accounts do.
+pub async fn verify_dummy_password(password: String) {
+    verify_password(password, DUMMY_PASSWORD_HASH.to_string()).await;
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+const USERNAME_MIN_LEN: usize = 3;
+const USERNAME_MAX_LEN: usize = 32;
+const RESERVED_USERNAMES: &[&str] = &[
+    "admin", "administrator", "root", "system", "server", "moderator",
+    "support", "you", "null", "undefined", "anonymous",
+];
+
+#[derive(Debug, PartialEq)]
+pub enum UsernameError {
+    Length,
+    Characters,
+    Reserved,
+}
+
+impl UsernameError {
+    pub fn message(&self) -> String {
+        match self {
+            UsernameError::Length => format!(
+                "Username must be between {} and {} characters long",
+                USERNAME_MIN_LEN, USERNAME_MAX_LEN
+            ),
+            UsernameError::Characters => {
+                "Username may only contain letters, digits, '_', '-' and '.', and must start with a letter or digit".to_string()
+            }
+            UsernameError::Reserved => "This username is reserved".to_string(),
+        }
+    }
+}
+
+/// Applies NFKC normalization so that compatibility variants (fullwidth letters,
+/// ligatures, etc.) collapse onto their plain form before lookup or storage.
+pub fn canonical_username(raw: &str) -> String {
+    raw.trim().nfkc().collect()
+}
+
+/// Normalizes and validates a username for registration. Only ASCII letters,
+/// digits and a few separators are accepted after normalization, which rules
+/// out lookalikes from other scripts; case-insensitive uniqueness is enforced
+/// by the database.
+pub fn validate_username(raw: &str) -> Result<String, UsernameError> {
+    let username = canonical_username(raw);
+
+    let len = username.chars().count();
+    if !(USERNAME_MIN_LEN..=USERNAME_MAX_LEN).contains(&len) {
+        return Err(UsernameError::Length);
+    }
+    if !username.starts_with(|c: char| c.is_ascii_alphanumeric())
+        || !username.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        return Err(UsernameError::Characters);
+    }
+    if RESERVED_USERNAMES.contains(&username.to_ascii_lowercase().as_str()) {
+        return Err(UsernameError::Reserved);
+    }
+    Ok(username)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dummy_hash_costs_as_much_as_a_real_one() {
+        let dummy = PasswordHash::new(DUMMY_PASSWORD_HASH).unwrap();
+        let params = argon2::Params::try_from(&dummy).unwrap();
+        let default = argon2::Params::default();
+        assert_eq!(dummy.algorithm, argon2::Algorithm::Argon2id.ident());
+        assert_eq!(
+            (params.m_cost(), params.t_cost(), params.p_cost()),
+            (default.m_cost(), default.t_cost(), default.p_cost())
+        );
+    }
+
+    #[test]
+    fn canonical_username_collapses_compatibility_forms() {
+        let cases = [
+            ("alice", "alice"),
+            ("  alice\t", "alice"),
+            ("\u{ff41}\u{ff4c}\u{ff49}\u{ff43}\u{ff45}", "alice"), // fullwidth
+            ("\u{ff21}dmin", "Admin"),
+            ("\u{fb01}nn", "finn"), // ligature
+            ("\u{430}lice", "\u{430}lice"), // Cyrillic а has no ASCII form
+        ];
+        for (raw, expected) in cases {
+            assert_eq!(canonical_username(raw), expected, "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn validate_username_accepts_and_rejects() {
+        let shortest = "a".repeat(USERNAME_MIN_LEN);
+        let longest = "a".repeat(USERNAME_MAX_LEN);
+        let too_short = "a".repeat(USERNAME_MIN_LEN - 1);
+        let too_long = "a".repeat(USERNAME_MAX_LEN + 1);
+        let cases = [
+            ("alice", Ok("alice")),
+            ("bob_42", Ok("bob_42")),
+            ("j.doe-x", Ok("j.doe-x")),
+            ("\u{ff41}\u{ff4c}\u{ff49}\u{ff43}\u{ff45}", Ok("alice")), // fullwidth
+            (&shortest, Ok(shortest.as_str())),
+            (&longest, Ok(longest.as_str())),
+            (&too_short, Err(UsernameError::Length)),
+            (&too_long, Err(UsernameError::Length)),
+            ("  ab  ", Err(UsernameError::Length)),
+            ("\u{430}lice", Err(UsernameError::Characters)), // Cyrillic а
+            ("\u{410}dmin", Err(UsernameError::Characters)), // Cyrillic А
+            ("_alice", Err(UsernameError::Characters)),
+            ("al ice", Err(UsernameError::Characters)),
+            ("Admin", Err(UsernameError::Reserved)),
+            ("\u{ff21}\u{ff24}\u{ff2d}\u{ff29}\u{ff2e}", Err(UsernameError::Reserved)), // fullwidth ADMIN
+        ];
+        for (raw, expected) in cases {
+            assert_eq!(validate_username(raw), expected.map(str::to_string), "{:?}", raw);
+        }
+    }
+}
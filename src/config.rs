@@ -0,0 +1,271 @@
+//! Runtime settings. Each one is taken from, in order of precedence: a
+//! command-line flag, a `CHAT_*` environment variable, the TOML config file,
+//! or the built-in default.
+
+use clap::Parser;
+use crate::auth::SameSite;
+use crate::outbox::OverflowPolicy;
+use crate::ratelimit::{Action, Rate};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Used when `--config` is not given, if it exists.
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// Upper bound for `history_page_size`, also the largest page `/api/chats/:id/messages` serves.
+pub const HISTORY_MAX_PAGE_SIZE: i64 = 100;
+
+#[derive(Parser)]
+#[command(about = "Real-time chat server")]
+pub struct Cli {
+    /// TOML config file [default: config.toml, if present]
+    #[arg(long, env = "CHAT_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address to listen on [default: 0.0.0.0:1578]
+    #[arg(long, env = "CHAT_BIND")]
+    bind: Option<SocketAddr>,
+    /// SQLite database file [default: database.db]
+    #[arg(long, env = "CHAT_DATABASE_PATH")]
+    database_path: Option<PathBuf>,
+    /// Directory served under /static [default: static]
+    #[arg(long, env = "CHAT_STATIC_DIR")]
+    static_dir: Option<PathBuf>,
+    /// How long a login lasts [default: 168]
+    #[arg(long, env = "CHAT_SESSION_TTL_HOURS")]
+    session_ttl_hours: Option<i64>,
+    /// Extend a session to session_ttl_hours from its last use, instead of from login [default: false]
+    #[arg(long, env = "CHAT_SESSION_SLIDING_EXPIRY")]
+    session_sliding_expiry: Option<bool>,
+    /// Lifetime of an invite created without `expires_in_hours` [default: 168]
+    #[arg(long, env = "CHAT_INVITE_TTL_HOURS")]
+    invite_ttl_hours: Option<i64>,
+    /// Longest lifetime an invite may be given [default: 720]
+    #[arg(long, env = "CHAT_INVITE_MAX_TTL_HOURS")]
+    invite_max_ttl_hours: Option<i64>,
+    /// Messages per history page [default: 50]
+    #[arg(long, env = "CHAT_HISTORY_PAGE_SIZE")]
+    history_page_size: Option<i64>,
+    /// Longest chat message, in characters [default: 4000]
+    #[arg(long, env = "CHAT_MAX_MESSAGE_CHARS")]
+    max_message_chars: Option<usize>,
+    /// Largest WebSocket frame accepted from a client, in bytes [default: 65536]
+    #[arg(long, env = "CHAT_MAX_FRAME_BYTES")]
+    max_frame_bytes: Option<usize>,
+    /// Accept chat sockets asking for the legacy plain-text protocol with v=0 [default: false]
+    #[arg(long, env = "CHAT_ALLOW_LEGACY_PROTOCOL")]
+    allow_legacy_protocol: Option<bool>,
+    /// Messages that may wait to be sent to one WebSocket before the slow-consumer policy applies [default: 256]
+    #[arg(long, env = "CHAT_OUTBOUND_QUEUE_CAPACITY")]
+    outbound_queue_capacity: Option<usize>,
+    /// What to do when a WebSocket's outbound queue is full [default: disconnect]
+    #[arg(long, env = "CHAT_SLOW_CONSUMER_POLICY")]
+    slow_consumer_policy: Option<OverflowPolicy>,
+    /// Most missed or changed messages replayed to a reconnecting client before it has to reload instead [default: 500]
+    #[arg(long, env = "CHAT_RESUME_MAX_MESSAGES")]
+    resume_max_messages: Option<i64>,
+    /// Seconds between WebSocket pings [default: 30]
+    #[arg(long, env = "CHAT_HEARTBEAT_INTERVAL_SECS")]
+    heartbeat_interval_secs: Option<u64>,
+    /// Pings in a row a client may leave unanswered before it is disconnected [default: 3]
+    #[arg(long, env = "CHAT_HEARTBEAT_MISSED_LIMIT")]
+    heartbeat_missed_limit: Option<u32>,
+    /// Take the client IP from the last X-Forwarded-For entry; only behind a reverse proxy that sets it [default: false]
+    #[arg(long, env = "CHAT_TRUST_FORWARDED_FOR")]
+    trust_forwarded_for: Option<bool>,
+    /// Mark the session cookie Secure, so browsers only send it over HTTPS [default: true]
+    #[arg(long, env = "CHAT_COOKIE_SECURE")]
+    cookie_secure: Option<bool>,
+    /// SameSite attribute of the session cookie [default: lax]
+    #[arg(long, env = "CHAT_COOKIE_SAME_SITE")]
+    cookie_same_site: Option<SameSite>,
+    /// Origins, besides the server's own, whose pages may open chat sockets and log in (comma-separated) [default: none]
+    #[arg(long, env = "CHAT_ALLOWED_ORIGINS", value_delimiter = ',')]
+    allowed_origins: Option<Vec<String>>,
+    /// Rate-limited socket events in a row before the socket is closed [default: 10]
+    #[arg(long, env = "CHAT_RATE_LIMIT_DISCONNECT_AFTER")]
+    rate_limit_disconnect_after: Option<u32>,
+    /// Seconds between purges of expired sessions and invites [default: 3600]
+    #[arg(long, env = "CHAT_JANITOR_INTERVAL_SECS")]
+    janitor_interval_secs: Option<u64>,
+    /// Rows deleted per statement when purging [default: 500]
+    #[arg(long, env = "CHAT_JANITOR_BATCH_SIZE")]
+    janitor_batch_size: Option<i64>,
+    /// How long shutdown waits for open WebSockets to finish their work, in seconds [default: 10]
+    #[arg(long, env = "CHAT_SHUTDOWN_TIMEOUT_SECS")]
+    shutdown_timeout_secs: Option<u64>,
+    /// Print the migrations startup would apply and exit without applying them
+    #[arg(long)]
+    pub pending_migrations: bool,
+    /// Delete expired sessions and invites once and exit
+    #[arg(long)]
+    pub purge_expired: bool,
+}
+
+/// The config file; every key is optional.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    bind: Option<SocketAddr>,
+    database_path: Option<PathBuf>,
+    static_dir: Option<PathBuf>,
+    session_ttl_hours: Option<i64>,
+    session_sliding_expiry: Option<bool>,
+    invite_ttl_hours: Option<i64>,
+    invite_max_ttl_hours: Option<i64>,
+    history_page_size: Option<i64>,
+    max_message_chars: Option<usize>,
+    max_frame_bytes: Option<usize>,
+    allow_legacy_protocol: Option<bool>,
+    outbound_queue_capacity: Option<usize>,
+    slow_consumer_policy: Option<OverflowPolicy>,
+    resume_max_messages: Option<i64>,
+    heartbeat_interval_secs: Option<u64>,
+    heartbeat_missed_limit: Option<u32>,
+    trust_forwarded_for: Option<bool>,
+    cookie_secure: Option<bool>,
+    cookie_same_site: Option<SameSite>,
+    allowed_origins: Option<Vec<String>>,
+    rate_limit_disconnect_after: Option<u32>,
+    // Only settable here: one table entry per action, e.g. `login = { burst = 10, per_minute = 10 }`
+    rate_limits: HashMap<Action, Rate>,
+    janitor_interval_secs: Option<u64>,
+    janitor_batch_size: Option<i64>,
+    shutdown_timeout_secs: Option<u64>,
+}
+
+pub struct Config {
+    pub bind: SocketAddr,
+    pub database_path: PathBuf,
+    pub static_dir: PathBuf,
+    pub session_ttl_hours: i64,
+    pub session_sliding_expiry: bool,
+    pub invite_ttl_hours: i64,
+    pub invite_max_ttl_hours: i64,
+    pub history_page_size: i64,
+    pub max_message_chars: usize,
+    pub max_frame_bytes: usize,
+    pub allow_legacy_protocol: bool,
+    pub outbound_queue_capacity: usize,
+    pub slow_consumer_policy: OverflowPolicy,
+    pub resume_max_messages: i64,
+    pub heartbeat_interval: Duration,
+    pub heartbeat_missed_limit: u32,
+    pub trust_forwarded_for: bool,
+    pub cookie_secure: bool,
+    pub cookie_same_site: SameSite,
+    pub allowed_origins: Vec<String>,
+    pub rate_limit_disconnect_after: u32,
+    pub rate_limits: HashMap<Action, Rate>,
+    pub janitor_interval: Duration,
+    pub janitor_batch_size: i64,
+    pub shutdown_timeout: Duration,
+}
+
+impl Config {
+    /// Merges the flags in `cli` over the config file and defaults, and validates the result.
+    pub fn load(cli: &Cli) -> Result<Config, String> {
+        let file = match &cli.config {
+            Some(path) => read_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => read_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => FileConfig::default(),
+        };
+
+        let mut rate_limits = Action::default_rates();
+        rate_limits.extend(file.rate_limits);
+
+        let config = Config {
+            bind: cli.bind.or(file.bind).unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 1578))),
+            database_path: cli.database_path.clone().or(file.database_path).unwrap_or_else(|| "database.db".into()),
+            static_dir: cli.static_dir.clone().or(file.static_dir).unwrap_or_else(|| "static".into()),
+            session_ttl_hours: cli.session_ttl_hours.or(file.session_ttl_hours).unwrap_or(7 * 24),
+            session_sliding_expiry: cli.session_sliding_expiry.or(file.session_sliding_expiry).unwrap_or(false),
+            invite_ttl_hours: cli.invite_ttl_hours.or(file.invite_ttl_hours).unwrap_or(7 * 24),
+            invite_max_ttl_hours: cli.invite_max_ttl_hours.or(file.invite_max_ttl_hours).unwrap_or(30 * 24),
+            history_page_size: cli.history_page_size.or(file.history_page_size).unwrap_or(50),
+            max_message_chars: cli.max_message_chars.or(file.max_message_chars).unwrap_or(4000),
+            max_frame_bytes: cli.max_frame_bytes.or(file.max_frame_bytes).unwrap_or(64 * 1024),
+            allow_legacy_protocol: cli.allow_legacy_protocol.or(file.allow_legacy_protocol).unwrap_or(false),
+            outbound_queue_capacity: cli.outbound_queue_capacity.or(file.outbound_queue_capacity).unwrap_or(256),
+            slow_consumer_policy: cli.slow_consumer_policy.or(file.slow_consumer_policy).unwrap_or(OverflowPolicy::Disconnect),
+            resume_max_messages: cli.resume_max_messages.or(file.resume_max_messages).unwrap_or(500),
+            heartbeat_interval: Duration::from_secs(cli.heartbeat_interval_secs.or(file.heartbeat_interval_secs).unwrap_or(30)),
+            heartbeat_missed_limit: cli.heartbeat_missed_limit.or(file.heartbeat_missed_limit).unwrap_or(3),
+            trust_forwarded_for: cli.trust_forwarded_for.or(file.trust_forwarded_for).unwrap_or(false),
+            cookie_secure: cli.cookie_secure.or(file.cookie_secure).unwrap_or(true),
+            cookie_same_site: cli.cookie_same_site.or(file.cookie_same_site).unwrap_or(SameSite::Lax),
+            allowed_origins: cli.allowed_origins.clone().or(file.allowed_origins).unwrap_or_default(),
+            rate_limit_disconnect_after: cli.rate_limit_disconnect_after.or(file.rate_limit_disconnect_after).unwrap_or(10),
+            rate_limits,
+            janitor_interval: Duration::from_secs(cli.janitor_interval_secs.or(file.janitor_interval_secs).unwrap_or(3600)),
+            janitor_batch_size: cli.janitor_batch_size.or(file.janitor_batch_size).unwrap_or(500),
+            shutdown_timeout: Duration::from_secs(cli.shutdown_timeout_secs.or(file.shutdown_timeout_secs).unwrap_or(10)),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !self.static_dir.is_dir() {
+            return Err(format!("static_dir {} is not a directory", self.static_dir.display()));
+        }
+        if self.session_ttl_hours < 1 {
+            return Err("session_ttl_hours must be at least 1".to_string());
+        }
+        if self.invite_max_ttl_hours < 1 {
+            return Err("invite_max_ttl_hours must be at least 1".to_string());
+        }
+        if !(1..=self.invite_max_ttl_hours).contains(&self.invite_ttl_hours) {
+            return Err(format!("invite_ttl_hours must be between 1 and invite_max_ttl_hours ({})", self.invite_max_ttl_hours));
+        }
+        if !(1..=HISTORY_MAX_PAGE_SIZE).contains(&self.history_page_size) {
+            return Err(format!("history_page_size must be between 1 and {}", HISTORY_MAX_PAGE_SIZE));
+        }
+        if self.max_message_chars < 1 {
+            return Err("max_message_chars must be at least 1".to_string());
+        }
+        // A UTF-8 character is at most 4 bytes, plus room for the JSON envelope
+        if self.max_frame_bytes < self.max_message_chars * 4 + 1024 {
+            return Err(format!(
+                "max_frame_bytes must be at least {} to fit a message of max_message_chars",
+                self.max_message_chars * 4 + 1024
+            ));
+        }
+        if self.outbound_queue_capacity < 1 {
+            return Err("outbound_queue_capacity must be at least 1".to_string());
+        }
+        if self.resume_max_messages < 0 {
+            return Err("resume_max_messages must not be negative".to_string());
+        }
+        if self.heartbeat_interval.is_zero() {
+            return Err("heartbeat_interval_secs must be at least 1".to_string());
+        }
+        if self.heartbeat_missed_limit < 1 {
+            return Err("heartbeat_missed_limit must be at least 1".to_string());
+        }
+        // Browsers reject SameSite=None cookies that aren't Secure
+        if self.cookie_same_site == SameSite::None && !self.cookie_secure {
+            return Err("cookie_same_site = \"none\" requires cookie_secure".to_string());
+        }
+        if self.rate_limit_disconnect_after < 1 {
+            return Err("rate_limit_disconnect_after must be at least 1".to_string());
+        }
+        if self.janitor_interval.is_zero() {
+            return Err("janitor_interval_secs must be at least 1".to_string());
+        }
+        if self.janitor_batch_size < 1 {
+            return Err("janitor_batch_size must be at least 1".to_string());
+        }
+        if self.rate_limits.values().any(|rate| rate.burst < 1 || rate.per_minute < 1) {
+            return Err("rate_limits entries need a burst and per_minute of at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn read_file(path: &Path) -> Result<FileConfig, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    toml::from_str(&text).map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+}
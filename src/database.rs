@@ -1,279 +1,1068 @@
-use sqlite;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+use crate::migrations::{self, MigrationError};
 use crate::template::MessageView;
 
+#[derive(Clone, Copy)]
+pub enum MessageCursor {
+    Latest,
+    Before(i64),
+    After(i64),
+    Around(i64),
+}
+
+/// A member's role in a chat, ordered from least to most privileged.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatRole {
+    Member,
+    Admin,
+    Owner,
+}
+
+impl ChatRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChatRole::Member => "member",
+            ChatRole::Admin => "admin",
+            ChatRole::Owner => "owner",
+        }
+    }
+
+    fn parse(role: &str) -> Self {
+        match role {
+            "owner" => ChatRole::Owner,
+            "admin" => ChatRole::Admin,
+            _ => ChatRole::Member,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct SearchFilters {
+    pub chat_id: Option<i64>,
+    pub author: Option<String>,
+    pub from: Option<String>, // YYYY-MM-DD, inclusive
+    pub to: Option<String>,   // YYYY-MM-DD, inclusive
+}
+
+pub struct SearchHit {
+    pub id: i64,
+    pub chat_id: i64,
+    pub chat_name: String,
+    pub author: String,
+    pub sent_at: String,
+    // Matched terms are wrapped in U+E000 / U+E001
+    pub snippet: String,
+}
+
+pub enum InviteRedemption {
+    Joined(i64),
+    AlreadyMember(i64),
+    // Unknown or expired
+    Invalid,
+    Revoked,
+    Exhausted,
+}
+
+/// A session that passed `validate_session`.
+pub struct ValidSession {
+    pub session_id: i64,
+    pub user_id: i64,
+    pub username: String,
+    pub csrf_token: String,
+}
+
+/// Tables whose rows stop mattering once `expires_at` has passed.
+#[derive(Clone, Copy)]
+pub enum Expiring {
+    Sessions,
+    InviteCodes,
+}
+
+/// Where and when a session was opened, for the sessions page.
+pub struct SessionView {
+    pub id: i64,
+    pub created_at: Option<String>,
+    pub last_seen_at: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub expires_at: String,
+}
+
+/// How often a session's `last_seen_at` is written, at most.
+const SESSION_TOUCH_INTERVAL: &str = "-1 minutes";
+
+#[derive(serde::Serialize)]
+pub struct InviteView {
+    pub code: String,
+    pub creator: Option<String>,
+    pub max_uses: Option<i64>,
+    pub use_count: i64,
+    pub created_at: String,
+    pub expires_at: String,
+}
+
+/// Number of connections kept open. WAL lets readers run alongside the one
+/// writer; writers queue on SQLite's lock for up to `BUSY_TIMEOUT_MS`.
+const POOL_SIZE: usize = 4;
+const BUSY_TIMEOUT_MS: usize = 5000;
+
+/// Handle to the database. Queries run on Tokio's blocking thread pool, each on
+/// a connection borrowed from a small pool, so a slow query never stalls the
+/// async workers.
+#[derive(Clone)]
 pub struct Database {
-    connection: Arc<Mutex<sqlite::Connection>>,
+    pool: Arc<Pool>,
 }
 
-impl Clone for Database {
-    fn clone(&self) -> Self {
-        Database {
-            connection: Arc::clone(&self.connection),
+struct Pool {
+    idle: Mutex<Vec<sqlite::Connection>>,
+    // One permit per connection, so tasks wait for a free one without blocking a thread
+    permits: Arc<Semaphore>,
+}
+
+impl Pool {
+    /// Takes an idle connection. Only called while holding a permit, so there always is one.
+    fn checkout(&self) -> PooledConnection<'_> {
+        let conn = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        PooledConnection { pool: self, conn: Some(conn.expect("connection pool is empty")) }
+    }
+}
+
+/// A borrowed connection, returned to the pool when dropped.
+struct PooledConnection<'a> {
+    pool: &'a Pool,
+    conn: Option<sqlite::Connection>,
+}
+
+impl std::ops::Deref for PooledConnection<'_> {
+    type Target = sqlite::Connection;
+
+    fn deref(&self) -> &sqlite::Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            if std::thread::panicking() {
+                // The query panicked, possibly halfway through a transaction
+                let _ = conn.execute("ROLLBACK;");
+            }
+            self.pool.idle.lock().unwrap_or_else(|e| e.into_inner()).push(conn);
         }
     }
 }
 
+fn open_connection(path: &Path) -> Result<sqlite::Connection, sqlite::Error> {
+    let mut conn = sqlite::open(path)?;
+    conn.set_busy_timeout(BUSY_TIMEOUT_MS)?;
+    conn.execute(
+        "PRAGMA journal_mode = WAL;
+         PRAGMA synchronous = NORMAL;
+         PRAGMA foreign_keys = ON;"
+    )?;
+    Ok(conn)
+}
+
 impl Database {
-    pub fn new() -> Self {
-        let conn = match sqlite::open("database.db") {
-            Ok(conn) => conn,
+    pub fn new(path: &Path) -> Self {
+        let connections = (0..POOL_SIZE)
+            .map(|_| open_connection(path))
+            .collect::<Result<Vec<_>, _>>();
+        let connections = match connections {
+            Ok(connections) => connections,
             Err(e) => panic!("Error opening database: {}", e),
         };
 
-        conn.execute("PRAGMA foreign_keys = ON;").ok();
-
-        Database { 
-            connection: Arc::new(Mutex::new(conn)),
+        Database {
+            pool: Arc::new(Pool {
+                idle: Mutex::new(connections),
+                permits: Arc::new(Semaphore::new(POOL_SIZE)),
+            }),
         }
     }
 
-    pub fn create(&self) -> Result<(), sqlite::Error> {
-        println!("Creating database schema...");
-        self.connection.lock().unwrap().execute(
-            "
-            CREATE TABLE IF NOT EXISTS Messages (
-                messageID INTEGER PRIMARY KEY,
-                message_text TEXT NOT NULL,
-                username TEXT NOT NULL,
-                chatID INTEGER NOT NULL,
-                timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
-                FOREIGN KEY(chatID) REFERENCES Chats(chatID) ON DELETE CASCADE
-            );
-            CREATE TABLE IF NOT EXISTS Users (
-                userID INTEGER PRIMARY KEY,
-                username TEXT NOT NULL,
-                password_hash TEXT NOT NULL
-            );
-            CREATE TABLE IF NOT EXISTS Sessions (
-                sessionID INTEGER PRIMARY KEY,
-                userID INTEGER NOT NULL,
-                session_token TEXT NOT NULL,
-                expires_at DATETIME NOT NULL,
-                FOREIGN KEY(userID) REFERENCES Users(userID)
-            );
-            CREATE TABLE IF NOT EXISTS Chats (
-                chatID INTEGER PRIMARY KEY,
-                chat_name TEXT NOT NULL
-            );
-            CREATE TABLE IF NOT EXISTS ChatMembers (
-                chatID INTEGER NOT NULL,
-                userID INTEGER NOT NULL,
-                PRIMARY KEY (chatID, userID),
-                FOREIGN KEY(chatID) REFERENCES Chats(chatID) ON DELETE CASCADE,
-                FOREIGN KEY(userID) REFERENCES Users(userID) ON DELETE CASCADE
-            ) WITHOUT ROWID;
-            CREATE TABLE IF NOT EXISTS InviteCodes (
-                code TEXT PRIMARY KEY,
-                chatID INTEGER NOT NULL,
-                expires_at DATETIME NOT NULL,
-                FOREIGN KEY(chatID) REFERENCES Chats(chatID) ON DELETE CASCADE
-            );
-            ",
-        )
-    }
-
-    pub fn insert_message(&self, message_text: &str, username: &str, chat_id: i64) -> Result<(), sqlite::Error> {
-        let conn = self.connection.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "INSERT INTO Messages (message_text, username, chatID) VALUES (?, ?, ?);"
-        )?;
-        stmt.bind((1, message_text))?;
-        stmt.bind((2, username))?;
-        stmt.bind((3, chat_id))?;
-        stmt.next()?;
-        Ok(())
-    }
-
-    pub fn get_user(&self, username: &str) -> Result<Option<(i64, String)>, sqlite::Error> {
-        let conn = self.connection.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT userID, username FROM Users WHERE username = ?;"
-        )?;
-        stmt.bind((1, username))?;
-        if let sqlite::State::Row = stmt.next()? {
-            let user_id: i64 = stmt.read(0)?;
-            let username: String = stmt.read(1)?;
-            Ok(Some((user_id, username)))
-        } else {
-            Ok(None)
+    /// Runs `work` on a pooled connection on the blocking thread pool.
+    async fn run<T, E, F>(&self, work: F) -> Result<T, E>
+    where
+        F: FnOnce(&sqlite::Connection) -> Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: From<sqlite::Error> + Send + 'static,
+    {
+        let permit = Arc::clone(&self.pool.permits).acquire_owned().await.expect("connection pool closed");
+        let pool = Arc::clone(&self.pool);
+        let task = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let conn = pool.checkout();
+            work(&conn)
+        });
+        match task.await {
+            Ok(result) => result,
+            Err(e) => Err(sqlite::Error {
+                code: None,
+                message: Some(format!("Database task failed: {}", e)),
+            }.into()),
         }
     }
 
-    pub fn check_password(&self, username: &str, password_hash: &str) -> bool {
-        let conn = self.connection.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT password_hash FROM Users WHERE username = ?;"
-        ).unwrap();
+    /// Brings the schema up to date, see `migrations`.
+    pub async fn migrate(&self) -> Result<(), MigrationError> {
+        self.run(migrations::migrate).await
+    }
 
-        // Return false if binding fails
-        if stmt.bind((1, username)).is_err() {
-            return false;
-        }
+    /// Migrations `migrate` would apply, as (version, description).
+    pub async fn pending_migrations(&self) -> Result<Vec<(i64, &'static str)>, MigrationError> {
+        self.run(|conn| {
+            let pending = migrations::pending(conn)?;
+            Ok(pending.into_iter().map(|(version, migration)| (version, migration.description)).collect())
+        }).await
+    }
 
-        if let sqlite::State::Row = stmt.next().unwrap_or(sqlite::State::Done) {
-            let stored_hash: String = stmt.read(0).unwrap_or_default();
-            stored_hash == password_hash
-        } else {
-            false
-        }
+    /// Copies the WAL back into the database file and truncates it, so nothing
+    /// is left in `database.db-wal` when the server stops.
+    pub async fn checkpoint(&self) -> Result<(), sqlite::Error> {
+        self.run(|conn| conn.execute("PRAGMA wal_checkpoint(TRUNCATE);")).await
     }
 
-    pub fn add_user(&self, username: &str, password_hash: &str) -> Result<(), sqlite::Error> {
-        let conn = self.connection.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "INSERT INTO Users (username, password_hash) VALUES (?, ?);"
-        )?;
-        stmt.bind((1, username))?;
-        stmt.bind((2, password_hash))?;
-        stmt.next()?;
-        Ok(())
-    }
-
-    pub fn create_session(&self, user_id: i64, session_token: &str) -> Result<(), sqlite::Error> {
-        let conn = self.connection.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "INSERT INTO Sessions (userID, session_token, expires_at) VALUES (?, ?, datetime('now', '+7 days'));"
-        )?;
-        stmt.bind((1, user_id))?;
-        stmt.bind((2, session_token))?;
-        stmt.next()?;
-        Ok(())
-    }
-
-    pub fn validate_session(&self, session_token: &str) -> Result<Option<(i64, String)>, sqlite::Error> {
-        let conn = self.connection.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT s.userID, u.username
-                        FROM Sessions AS s
-                        JOIN Users AS u ON u.userID = s.userID
-                        WHERE session_token = ? AND expires_at > datetime('now');"
-        )?;
-        stmt.bind((1, session_token))?;
-        if let sqlite::State::Row = stmt.next()? {
-            let user_id: i64 = stmt.read(0)?;
-            let username: String = stmt.read(1)?;
-            Ok(Some((user_id, username)))
-        } else {
-            Ok(None)
-        }
+    /// Deletes up to `limit` expired rows of `table` and returns how many went.
+    pub async fn purge_expired(&self, table: Expiring, limit: i64) -> Result<usize, sqlite::Error> {
+        let (table, key) = match table {
+            Expiring::Sessions => ("Sessions", "sessionID"),
+            Expiring::InviteCodes => ("InviteCodes", "code"),
+        };
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(format!(
+                "DELETE FROM {table} WHERE {key} IN (
+                                SELECT {key} FROM {table} WHERE expires_at <= datetime('now') LIMIT ?
+                            );"
+            ))?;
+            stmt.bind((1, limit))?;
+            stmt.next()?;
+            Ok(conn.change_count())
+        }).await
+    }
+
+    /// The database's clock as ISO-8601, the same clock `edited_at` and
+    /// `deleted_at` are set from.
+    pub async fn current_time(&self) -> Result<String, sqlite::Error> {
+        self.run(|conn| {
+            let mut stmt = conn.prepare("SELECT strftime('%Y-%m-%dT%H:%M:%SZ', 'now');")?;
+            stmt.next()?;
+            stmt.read(0)
+        }).await
+    }
+
+    /// Stores a message and returns its ID and ISO-8601 timestamp.
+    pub async fn insert_message(&self, message_text: &str, username: &str, chat_id: i64) -> Result<(i64, String), sqlite::Error> {
+        let message_text = message_text.to_string();
+        let username = username.to_string();
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "INSERT INTO Messages (message_text, username, chatID) VALUES (?, ?, ?)
+                            RETURNING messageID, strftime('%Y-%m-%dT%H:%M:%SZ', timestamp);"
+            )?;
+            stmt.bind((1, message_text.as_str()))?;
+            stmt.bind((2, username.as_str()))?;
+            stmt.bind((3, chat_id))?;
+            match stmt.next()? {
+                sqlite::State::Row => Ok((stmt.read(0)?, stmt.read(1)?)),
+                _ => unreachable!(),
+            }
+        }).await
+    }
+
+    pub async fn get_user(&self, username: &str) -> Result<Option<(i64, String)>, sqlite::Error> {
+        let username = username.to_string();
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT userID, username FROM Users WHERE username = ? COLLATE NOCASE;"
+            )?;
+            stmt.bind((1, username.as_str()))?;
+            if let sqlite::State::Row = stmt.next()? {
+                let user_id: i64 = stmt.read(0)?;
+                let username: String = stmt.read(1)?;
+                Ok(Some((user_id, username)))
+            } else {
+                Ok(None)
+            }
+        }).await
+    }
+
+    pub async fn get_password_hash(&self, username: &str) -> Result<Option<(i64, String)>, sqlite::Error> {
+        let username = username.to_string();
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT userID, password_hash FROM Users WHERE username = ? COLLATE NOCASE;"
+            )?;
+            stmt.bind((1, username.as_str()))?;
+            if let sqlite::State::Row = stmt.next()? {
+                let user_id: i64 = stmt.read(0)?;
+                let password_hash: String = stmt.read(1)?;
+                Ok(Some((user_id, password_hash)))
+            } else {
+                Ok(None)
+            }
+        }).await
+    }
+
+    pub async fn update_password_hash(&self, user_id: i64, password_hash: &str) -> Result<(), sqlite::Error> {
+        let password_hash = password_hash.to_string();
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "UPDATE Users SET password_hash = ? WHERE userID = ?;"
+            )?;
+            stmt.bind((1, password_hash.as_str()))?;
+            stmt.bind((2, user_id))?;
+            stmt.next()?;
+            Ok(())
+        }).await
+    }
+
+    pub async fn add_user(&self, username: &str, password_hash: &str) -> Result<i64, sqlite::Error> {
+        let username = username.to_string();
+        let password_hash = password_hash.to_string();
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "INSERT INTO Users (username, password_hash) VALUES (?, ?) RETURNING userID;"
+            )?;
+            stmt.bind((1, username.as_str()))?;
+            stmt.bind((2, password_hash.as_str()))?;
+            match stmt.next()? { sqlite::State::Row => stmt.read(0), _ => unreachable!() }
+        }).await
+    }
+
+    pub async fn create_session(
+        &self,
+        user_id: i64,
+        session_token: &str,
+        csrf_token: &str,
+        ttl_hours: i64,
+        user_agent: Option<&str>,
+        ip: &str,
+    ) -> Result<(), sqlite::Error> {
+        let session_token = session_token.to_string();
+        let csrf_token = csrf_token.to_string();
+        let user_agent = user_agent.map(str::to_string);
+        let ip = ip.to_string();
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "INSERT INTO Sessions (userID, session_token, csrf_token, expires_at, created_at, last_seen_at, user_agent, ip)
+                            VALUES (?, ?, ?, datetime('now', '+' || ? || ' hours'), CURRENT_TIMESTAMP, CURRENT_TIMESTAMP, ?, ?);"
+            )?;
+            stmt.bind((1, user_id))?;
+            stmt.bind((2, session_token.as_str()))?;
+            stmt.bind((3, csrf_token.as_str()))?;
+            stmt.bind((4, ttl_hours))?;
+            stmt.bind((5, user_agent.as_deref()))?;
+            stmt.bind((6, ip.as_str()))?;
+            stmt.next()?;
+            Ok(())
+        }).await
+    }
+
+    /// Looks up a live session and records that it was just used. With
+    /// `sliding_ttl_hours` that also pushes its expiry to that many hours from now.
+    /// Both are written at most once a minute per session.
+    pub async fn validate_session(&self, session_token: &str, sliding_ttl_hours: Option<i64>) -> Result<Option<ValidSession>, sqlite::Error> {
+        let session_token = session_token.to_string();
+        self.run(move |conn| {
+            let session = {
+                let mut stmt = conn.prepare(
+                    "SELECT s.sessionID, s.userID, u.username, s.csrf_token
+                                FROM Sessions AS s
+                                JOIN Users AS u ON u.userID = s.userID
+                                WHERE session_token = ? AND expires_at > datetime('now');"
+                )?;
+                stmt.bind((1, session_token.as_str()))?;
+                if let sqlite::State::Row = stmt.next()? {
+                    ValidSession {
+                        session_id: stmt.read(0)?,
+                        user_id: stmt.read(1)?,
+                        username: stmt.read(2)?,
+                        csrf_token: stmt.read(3)?,
+                    }
+                } else {
+                    return Ok(None);
+                }
+            };
+
+            let mut stmt = conn.prepare(format!(
+                "UPDATE Sessions SET last_seen_at = CURRENT_TIMESTAMP{}
+                            WHERE sessionID = ? AND (last_seen_at IS NULL OR last_seen_at < datetime('now', '{}'));",
+                if sliding_ttl_hours.is_some() { ", expires_at = datetime('now', '+' || ? || ' hours')" } else { "" },
+                SESSION_TOUCH_INTERVAL
+            ))?;
+            match sliding_ttl_hours {
+                Some(ttl_hours) => {
+                    stmt.bind((1, ttl_hours))?;
+                    stmt.bind((2, session.session_id))?;
+                }
+                None => stmt.bind((1, session.session_id))?,
+            }
+            stmt.next()?;
+            Ok(Some(session))
+        }).await
+    }
+
+    /// The user's sessions that haven't expired, most recently used first.
+    pub async fn get_user_sessions(&self, user_id: i64) -> Result<Vec<SessionView>, sqlite::Error> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT sessionID, strftime('%Y-%m-%d %H:%M', created_at), strftime('%Y-%m-%d %H:%M', last_seen_at),
+                                user_agent, ip, strftime('%Y-%m-%d %H:%M', expires_at)
+                            FROM Sessions
+                            WHERE userID = ? AND expires_at > datetime('now')
+                            ORDER BY last_seen_at DESC, sessionID DESC;"
+            )?;
+            stmt.bind((1, user_id))?;
+            let mut sessions = Vec::new();
+            while let sqlite::State::Row = stmt.next()? {
+                sessions.push(SessionView {
+                    id: stmt.read(0)?,
+                    created_at: stmt.read(1)?,
+                    last_seen_at: stmt.read(2)?,
+                    user_agent: stmt.read(3)?,
+                    ip: stmt.read(4)?,
+                    expires_at: stmt.read(5)?,
+                });
+            }
+            Ok(sessions)
+        }).await
+    }
+
+    /// Ends one of the user's sessions. Returns false if it wasn't theirs or is already gone.
+    pub async fn delete_session(&self, user_id: i64, session_id: i64) -> Result<bool, sqlite::Error> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare("DELETE FROM Sessions WHERE sessionID = ? AND userID = ? RETURNING sessionID;")?;
+            stmt.bind((1, session_id))?;
+            stmt.bind((2, user_id))?;
+            Ok(matches!(stmt.next()?, sqlite::State::Row))
+        }).await
+    }
+
+    /// Ends every session of the user except `keep`, returning the IDs of those ended.
+    pub async fn delete_other_sessions(&self, user_id: i64, keep: i64) -> Result<Vec<i64>, sqlite::Error> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare("DELETE FROM Sessions WHERE userID = ? AND sessionID != ? RETURNING sessionID;")?;
+            stmt.bind((1, user_id))?;
+            stmt.bind((2, keep))?;
+            let mut ended = Vec::new();
+            while let sqlite::State::Row = stmt.next()? {
+                ended.push(stmt.read(0)?);
+            }
+            Ok(ended)
+        }).await
+    }
+
+    /// Messages up to `up_to` that were edited or deleted at or after `since`
+    /// (ISO-8601), oldest first, at most `limit` of them.
+    pub async fn get_changed_messages(&self, chat_id: i64, up_to: i64, since: &str, limit: i64) -> Result<Vec<MessageView>, sqlite::Error> {
+        let since = since.to_string();
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(format!(
+                "SELECT {}
+                            FROM Messages AS m
+                            WHERE m.chatID = ?1 AND m.messageID <= ?2
+                                AND (m.edited_at >= datetime(?3) OR m.deleted_at >= datetime(?3))
+                            ORDER BY m.messageID LIMIT ?4;",
+                MESSAGE_VIEW_COLUMNS
+            ))?;
+            stmt.bind((1, chat_id))?;
+            stmt.bind((2, up_to))?;
+            stmt.bind((3, since.as_str()))?;
+            stmt.bind((4, limit))?;
+            read_messages(&mut stmt)
+        }).await
+    }
+
+    /// Returns up to `limit` messages around `cursor`, oldest first. Ordering is by
+    /// `messageID` so messages sharing a timestamp keep their insertion order.
+    pub async fn get_messages(&self, chat_id: i64, cursor: MessageCursor, limit: i64) -> Result<Vec<MessageView>, sqlite::Error> {
+        self.run(move |conn| {
+            let before = |id: i64| -> Result<Vec<MessageView>, sqlite::Error> {
+                let mut messages = query_messages(conn, chat_id, "m.messageID < ?", "DESC", id, limit)?;
+                messages.reverse();
+                Ok(messages)
+            };
+            match cursor {
+                MessageCursor::Latest => before(i64::MAX),
+                MessageCursor::Before(id) => before(id),
+                MessageCursor::After(id) => query_messages(conn, chat_id, "m.messageID > ?", "ASC", id, limit),
+                MessageCursor::Around(id) => {
+                    // The anchor itself counts towards the older half
+                    let mut messages = query_messages(conn, chat_id, "m.messageID <= ?", "DESC", id, limit - limit / 2)?;
+                    messages.reverse();
+                    messages.extend(query_messages(conn, chat_id, "m.messageID > ?", "ASC", id, limit / 2)?);
+                    Ok(messages)
+                }
+            }
+        }).await
+    }
+
+    /// Returns the author of a message in `chat_id` and whether it has been deleted.
+    pub async fn get_message_author(&self, message_id: i64, chat_id: i64) -> Result<Option<(String, bool)>, sqlite::Error> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT username, deleted_at IS NOT NULL FROM Messages WHERE messageID = ? AND chatID = ?;"
+            )?;
+            stmt.bind((1, message_id))?;
+            stmt.bind((2, chat_id))?;
+            if let sqlite::State::Row = stmt.next()? {
+                let username: String = stmt.read(0)?;
+                let deleted = stmt.read::<i64, _>(1)? != 0;
+                Ok(Some((username, deleted)))
+            } else {
+                Ok(None)
+            }
+        }).await
+    }
+
+    /// Replaces the text of a live message written by `username`, keeping the old
+    /// text in `MessageEdits`. Returns the new `edited_at`, or None if nothing matched.
+    pub async fn edit_message(&self, message_id: i64, username: &str, message_text: &str) -> Result<Option<String>, sqlite::Error> {
+        let username = username.to_string();
+        let message_text = message_text.to_string();
+        self.run(move |conn| {
+            transaction(conn, || {
+                {
+                    let mut stmt = conn.prepare(
+                        "INSERT INTO MessageEdits (messageID, previous_text)
+                                    SELECT messageID, message_text FROM Messages
+                                    WHERE messageID = ? AND username = ? AND deleted_at IS NULL;"
+                    )?;
+                    stmt.bind((1, message_id))?;
+                    stmt.bind((2, username.as_str()))?;
+                    stmt.next()?;
+                }
+                let mut stmt = conn.prepare(
+                    "UPDATE Messages SET message_text = ?, edited_at = CURRENT_TIMESTAMP
+                                WHERE messageID = ? AND username = ? AND deleted_at IS NULL
+                                RETURNING strftime('%Y-%m-%dT%H:%M:%SZ', edited_at);"
+                )?;
+                stmt.bind((1, message_text.as_str()))?;
+                stmt.bind((2, message_id))?;
+                stmt.bind((3, username.as_str()))?;
+                match stmt.next()? {
+                    sqlite::State::Row => Ok(Some(stmt.read(0)?)),
+                    sqlite::State::Done => Ok(None),
+                }
+            })
+        }).await
+    }
+
+    /// Turns a message written by `username` into a tombstone: the text and its
+    /// edit history are dropped, the row stays so IDs and ordering are preserved.
+    pub async fn delete_message(&self, message_id: i64, username: &str) -> Result<bool, sqlite::Error> {
+        let username = username.to_string();
+        self.run(move |conn| {
+            transaction(conn, || {
+                let deleted = {
+                    let mut stmt = conn.prepare(
+                        "UPDATE Messages SET message_text = '', deleted_at = CURRENT_TIMESTAMP
+                                    WHERE messageID = ? AND username = ? AND deleted_at IS NULL
+                                    RETURNING messageID;"
+                    )?;
+                    stmt.bind((1, message_id))?;
+                    stmt.bind((2, username.as_str()))?;
+                    matches!(stmt.next()?, sqlite::State::Row)
+                };
+                if deleted {
+                    let mut stmt = conn.prepare("DELETE FROM MessageEdits WHERE messageID = ?;")?;
+                    stmt.bind((1, message_id))?;
+                    stmt.next()?;
+                }
+                Ok(deleted)
+            })
+        }).await
     }
 
-    pub fn delete_session(&self, session_token: &str) -> Result<(), sqlite::Error> {
-        let conn = self.connection.lock().unwrap();
-        let mut stmt = conn.prepare("DELETE FROM Sessions WHERE session_token = ?;")?;
-        stmt.bind((1, session_token))?;
-        stmt.next()?;
-        Ok(())
-    }
-
-    pub fn get_messages(&self, chat_id:i64, limit: i64) -> Result<Vec<MessageView>, sqlite::Error> {
-        let conn = self.connection.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT m.username, m.message_text
-                        FROM Messages AS m
-                        JOIN Chats AS c ON c.chatID = m.chatID
-                        WHERE c.chatID = ?
-                        ORDER BY timestamp DESC LIMIT ?;"
-        )?;
-        stmt.bind((1, chat_id))?;
-        stmt.bind((2, limit))?;
-        
-        let mut messages = Vec::new();
-        while let sqlite::State::Row = stmt.next()? {
-            let username: String = stmt.read(0)?;
-            let message_text: String = stmt.read(1)?;
-            messages.push(MessageView { username, text: message_text });
+    /// Previous versions of a message, oldest first, as (text, replaced_at).
+    pub async fn get_message_edits(&self, message_id: i64) -> Result<Vec<(String, String)>, sqlite::Error> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT previous_text, strftime('%Y-%m-%dT%H:%M:%SZ', edited_at)
+                            FROM MessageEdits
+                            WHERE messageID = ?
+                            ORDER BY editID;"
+            )?;
+            stmt.bind((1, message_id))?;
+
+            let mut edits = Vec::new();
+            while let sqlite::State::Row = stmt.next()? {
+                edits.push((stmt.read(0)?, stmt.read(1)?));
+            }
+            Ok(edits)
+        }).await
+    }
+
+    /// Full-text search over live messages in chats `user_id` belongs to, best
+    /// matches first. `query` must already be a valid FTS5 expression.
+    pub async fn search_messages(&self, user_id: i64, query: &str, filters: &SearchFilters, limit: i64) -> Result<Vec<SearchHit>, sqlite::Error> {
+        let mut sql = String::from(
+            "SELECT m.messageID, m.chatID, c.chat_name, m.username,
+                    strftime('%Y-%m-%dT%H:%M:%SZ', m.timestamp),
+                    snippet(MessagesFTS, 0, char(57344), char(57345), '…', 12)
+                FROM MessagesFTS
+                JOIN Messages AS m ON m.messageID = MessagesFTS.rowid
+                JOIN Chats AS c ON c.chatID = m.chatID
+                JOIN ChatMembers AS cm ON cm.chatID = m.chatID AND cm.userID = ?
+                WHERE MessagesFTS MATCH ? AND m.deleted_at IS NULL"
+        );
+        let mut params: Vec<sqlite::Value> = vec![user_id.into(), query.into()];
+        if let Some(chat_id) = filters.chat_id {
+            sql.push_str(" AND m.chatID = ?");
+            params.push(chat_id.into());
+        }
+        if let Some(author) = &filters.author {
+            sql.push_str(" AND m.username = ? COLLATE NOCASE");
+            params.push(author.as_str().into());
         }
-        Ok(messages)
-    }
-
-    pub fn check_chat_membership(&self, user_id: i64, chat_id: i64) -> Result<bool, sqlite::Error> {
-        let conn = self.connection.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT 1 FROM ChatMembers WHERE userID = ? AND chatID = ?;"
-        )?;
-        stmt.bind((1, user_id))?;
-        stmt.bind((2, chat_id))?;
-        if let sqlite::State::Row = stmt.next()? {
-            Ok(true)
-        } else {
-            Ok(false)
+        if let Some(from) = &filters.from {
+            sql.push_str(" AND date(m.timestamp) >= ?");
+            params.push(from.as_str().into());
         }
+        if let Some(to) = &filters.to {
+            sql.push_str(" AND date(m.timestamp) <= ?");
+            params.push(to.as_str().into());
+        }
+        sql.push_str(" ORDER BY bm25(MessagesFTS), m.messageID DESC LIMIT ?;");
+        params.push(limit.into());
+
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(sql)?;
+            for (i, param) in params.into_iter().enumerate() {
+                stmt.bind((i + 1, param))?;
+            }
+
+            let mut hits = Vec::new();
+            while let sqlite::State::Row = stmt.next()? {
+                hits.push(SearchHit {
+                    id: stmt.read(0)?,
+                    chat_id: stmt.read(1)?,
+                    chat_name: stmt.read(2)?,
+                    author: stmt.read(3)?,
+                    sent_at: stmt.read(4)?,
+                    snippet: stmt.read(5)?,
+                });
+            }
+            Ok(hits)
+        }).await
     }
 
-    pub fn get_user_chats(&self, user_id: i64) -> Result<Vec<(i64, String)>, sqlite::Error> {
-        let conn = self.connection.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT c.chatID, c.chat_name
-                        FROM Chats AS c
-                        JOIN ChatMembers AS cm ON cm.chatID = c.chatID
-                        WHERE cm.userID = ?;"
-        )?;
-        stmt.bind((1, user_id))?;
-
-        let mut chats = Vec::new();
-        while let sqlite::State::Row = stmt.next()? {
-            let chat_id: i64 = stmt.read(0)?;
-            let chat_name: String = stmt.read(1)?;
-            chats.push((chat_id, chat_name));
-        }
-        Ok(chats)
+    /// Whether the chat has any message older (`older == true`) or newer than `id`.
+    pub async fn has_messages_beyond(&self, chat_id: i64, id: i64, older: bool) -> Result<bool, sqlite::Error> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(if older {
+                "SELECT 1 FROM Messages WHERE chatID = ? AND messageID < ? LIMIT 1;"
+            } else {
+                "SELECT 1 FROM Messages WHERE chatID = ? AND messageID > ? LIMIT 1;"
+            })?;
+            stmt.bind((1, chat_id))?;
+            stmt.bind((2, id))?;
+            Ok(matches!(stmt.next()?, sqlite::State::Row))
+        }).await
     }
 
-    pub fn create_chat(&self, chat_name: &str, user_id: i64) -> Result<i64, sqlite::Error> {
-        let conn = self.connection.lock().unwrap();
+    pub async fn check_chat_membership(&self, user_id: i64, chat_id: i64) -> Result<bool, sqlite::Error> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT 1 FROM ChatMembers WHERE userID = ? AND chatID = ?;"
+            )?;
+            stmt.bind((1, user_id))?;
+            stmt.bind((2, chat_id))?;
+            if let sqlite::State::Row = stmt.next()? {
+                Ok(true)
+            } else {
+                Ok(false)
+            }
+        }).await
+    }
 
-        let chat_id: i64 = {
-            let mut stmt = conn.prepare("INSERT INTO Chats (chat_name) VALUES (?) RETURNING chatID;")?;
-            stmt.bind((1, chat_name))?;
-            match stmt.next()? { sqlite::State::Row => stmt.read(0)?, _ => unreachable!() }
-        };
+    pub async fn get_member_role(&self, user_id: i64, chat_id: i64) -> Result<Option<ChatRole>, sqlite::Error> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT role FROM ChatMembers WHERE userID = ? AND chatID = ?;"
+            )?;
+            stmt.bind((1, user_id))?;
+            stmt.bind((2, chat_id))?;
+            if let sqlite::State::Row = stmt.next()? {
+                let role: String = stmt.read(0)?;
+                Ok(Some(ChatRole::parse(&role)))
+            } else {
+                Ok(None)
+            }
+        }).await
+    }
 
-        {
+    /// Returns (userID, username, role, last_read_message_id) for every member, most privileged first.
+    pub async fn get_chat_members(&self, chat_id: i64) -> Result<Vec<(i64, String, ChatRole, i64)>, sqlite::Error> {
+        self.run(move |conn| {
             let mut stmt = conn.prepare(
-                "INSERT INTO ChatMembers (chatID, userID) VALUES (?, ?);"
+                "SELECT u.userID, u.username, cm.role, cm.last_read_message_id
+                            FROM ChatMembers AS cm
+                            JOIN Users AS u ON u.userID = cm.userID
+                            WHERE cm.chatID = ?
+                            ORDER BY CASE cm.role WHEN 'owner' THEN 0 WHEN 'admin' THEN 1 ELSE 2 END, u.username;"
             )?;
             stmt.bind((1, chat_id))?;
+
+            let mut members = Vec::new();
+            while let sqlite::State::Row = stmt.next()? {
+                let user_id: i64 = stmt.read(0)?;
+                let username: String = stmt.read(1)?;
+                let role: String = stmt.read(2)?;
+                let last_read: i64 = stmt.read(3)?;
+                members.push((user_id, username, ChatRole::parse(&role), last_read));
+            }
+            Ok(members)
+        }).await
+    }
+
+    /// Sets a non-owner role. Ownership only changes hands through `transfer_ownership`.
+    pub async fn set_member_role(&self, user_id: i64, chat_id: i64, role: ChatRole) -> Result<bool, sqlite::Error> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "UPDATE ChatMembers SET role = ? WHERE userID = ? AND chatID = ? AND role != 'owner' RETURNING userID;"
+            )?;
+            stmt.bind((1, role.as_str()))?;
             stmt.bind((2, user_id))?;
+            stmt.bind((3, chat_id))?;
+            Ok(matches!(stmt.next()?, sqlite::State::Row))
+        }).await
+    }
+
+    /// Makes `new_owner_id` the owner and demotes the current owner to admin.
+    /// Returns false if `new_owner_id` is not a member of the chat.
+    pub async fn transfer_ownership(&self, chat_id: i64, current_owner_id: i64, new_owner_id: i64) -> Result<bool, sqlite::Error> {
+        self.run(move |conn| {
+            transaction(conn, || {
+                let promoted = {
+                    let mut stmt = conn.prepare(
+                        "UPDATE ChatMembers SET role = 'owner' WHERE userID = ? AND chatID = ? RETURNING userID;"
+                    )?;
+                    stmt.bind((1, new_owner_id))?;
+                    stmt.bind((2, chat_id))?;
+                    matches!(stmt.next()?, sqlite::State::Row)
+                };
+                if !promoted {
+                    return Ok(false);
+                }
+                let mut stmt = conn.prepare(
+                    "UPDATE ChatMembers SET role = 'admin' WHERE userID = ? AND chatID = ?;"
+                )?;
+                stmt.bind((1, current_owner_id))?;
+                stmt.bind((2, chat_id))?;
+                stmt.next()?;
+                Ok(true)
+            })
+        }).await
+    }
+
+    pub async fn remove_chat_member(&self, user_id: i64, chat_id: i64) -> Result<bool, sqlite::Error> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "DELETE FROM ChatMembers WHERE userID = ? AND chatID = ? RETURNING userID;"
+            )?;
+            stmt.bind((1, user_id))?;
+            stmt.bind((2, chat_id))?;
+            Ok(matches!(stmt.next()?, sqlite::State::Row))
+        }).await
+    }
+
+    pub async fn rename_chat(&self, chat_id: i64, chat_name: &str) -> Result<(), sqlite::Error> {
+        let chat_name = chat_name.to_string();
+        self.run(move |conn| {
+            let mut stmt = conn.prepare("UPDATE Chats SET chat_name = ? WHERE chatID = ?;")?;
+            stmt.bind((1, chat_name.as_str()))?;
+            stmt.bind((2, chat_id))?;
+            stmt.next()?;
+            Ok(())
+        }).await
+    }
+
+    /// Deletes a chat; members, messages and invites go with it via ON DELETE CASCADE.
+    pub async fn delete_chat(&self, chat_id: i64) -> Result<(), sqlite::Error> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare("DELETE FROM Chats WHERE chatID = ?;")?;
+            stmt.bind((1, chat_id))?;
             stmt.next()?;
+            Ok(())
+        }).await
+    }
+
+    /// Returns (chatID, chat_name, unread) for every chat the user is in, where
+    /// `unread` counts other people's live messages past the user's read marker.
+    pub async fn get_user_chats(&self, user_id: i64) -> Result<Vec<(i64, String, i64)>, sqlite::Error> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT c.chatID, c.chat_name,
+                                (SELECT COUNT(*) FROM Messages AS m
+                                    WHERE m.chatID = c.chatID
+                                      AND m.messageID > cm.last_read_message_id
+                                      AND m.deleted_at IS NULL
+                                      AND m.username != u.username)
+                            FROM Chats AS c
+                            JOIN ChatMembers AS cm ON cm.chatID = c.chatID
+                            JOIN Users AS u ON u.userID = cm.userID
+                            WHERE cm.userID = ?;"
+            )?;
+            stmt.bind((1, user_id))?;
+
+            let mut chats = Vec::new();
+            while let sqlite::State::Row = stmt.next()? {
+                let chat_id: i64 = stmt.read(0)?;
+                let chat_name: String = stmt.read(1)?;
+                let unread: i64 = stmt.read(2)?;
+                chats.push((chat_id, chat_name, unread));
+            }
+            Ok(chats)
+        }).await
+    }
+
+    /// Moves the user's read marker forward to `message_id`, clamped to the newest
+    /// message in the chat. Returns the new marker, or None if it did not move.
+    pub async fn mark_read(&self, user_id: i64, chat_id: i64, message_id: i64) -> Result<Option<i64>, sqlite::Error> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "UPDATE ChatMembers
+                            SET last_read_message_id = MIN(?, (SELECT COALESCE(MAX(messageID), 0) FROM Messages WHERE chatID = ?))
+                            WHERE userID = ? AND chatID = ?
+                              AND last_read_message_id < MIN(?, (SELECT COALESCE(MAX(messageID), 0) FROM Messages WHERE chatID = ?))
+                            RETURNING last_read_message_id;"
+            )?;
+            stmt.bind((1, message_id))?;
+            stmt.bind((2, chat_id))?;
+            stmt.bind((3, user_id))?;
+            stmt.bind((4, chat_id))?;
+            stmt.bind((5, message_id))?;
+            stmt.bind((6, chat_id))?;
+            match stmt.next()? {
+                sqlite::State::Row => Ok(Some(stmt.read(0)?)),
+                sqlite::State::Done => Ok(None),
+            }
+        }).await
+    }
+
+    /// Creates a chat with `user_id` as its owner, both rows in one transaction.
+    pub async fn create_chat(&self, chat_name: &str, user_id: i64) -> Result<i64, sqlite::Error> {
+        let chat_name = chat_name.to_string();
+        self.run(move |conn| {
+            transaction(conn, || {
+                let chat_id: i64 = {
+                    let mut stmt = conn.prepare("INSERT INTO Chats (chat_name) VALUES (?) RETURNING chatID;")?;
+                    stmt.bind((1, chat_name.as_str()))?;
+                    match stmt.next()? { sqlite::State::Row => stmt.read(0)?, _ => unreachable!() }
+                };
+
+                let mut stmt = conn.prepare(
+                    "INSERT INTO ChatMembers (chatID, userID, role) VALUES (?, ?, 'owner');"
+                )?;
+                stmt.bind((1, chat_id))?;
+                stmt.bind((2, user_id))?;
+                stmt.next()?;
+                Ok(chat_id)
+            })
+        }).await
+    }
+
+    /// The chat an invite leads to, as (chat ID, chat name, whether the invite can
+    /// still be redeemed). None if the code is unknown.
+    pub async fn get_invite_chat(&self, code: &str) -> Result<Option<(i64, String, bool)>, sqlite::Error> {
+        let code = code.to_string();
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT c.chatID, c.chat_name,
+                                i.expires_at > datetime('now') AND i.revoked_at IS NULL
+                                    AND (i.max_uses IS NULL OR i.use_count < i.max_uses)
+                            FROM InviteCodes AS i
+                            JOIN Chats AS c ON c.chatID = i.chatID
+                            WHERE i.code = ?;"
+            )?;
+            stmt.bind((1, code.as_str()))?;
+            match stmt.next()? {
+                sqlite::State::Row => Ok(Some((stmt.read(0)?, stmt.read(1)?, stmt.read::<i64, _>(2)? != 0))),
+                sqlite::State::Done => Ok(None),
+            }
+        }).await
+    }
+
+    /// Joins `user_id` to the invite's chat, counting the use only if the user
+    /// was not already a member. Checking and incrementing happen in one transaction.
+    pub async fn redeem_invite_code(&self, code: &str, user_id: i64) -> Result<InviteRedemption, sqlite::Error> {
+        let code = code.to_string();
+        self.run(move |conn| {
+            transaction(conn, || {
+                let (chat_id, expired, revoked, exhausted) = {
+                    let mut stmt = conn.prepare(
+                        "SELECT chatID, expires_at <= datetime('now'), revoked_at IS NOT NULL,
+                                    max_uses IS NOT NULL AND use_count >= max_uses
+                                FROM InviteCodes WHERE code = ?;"
+                    )?;
+                    stmt.bind((1, code.as_str()))?;
+                    match stmt.next()? {
+                        sqlite::State::Row => (
+                            stmt.read::<i64, _>(0)?,
+                            stmt.read::<i64, _>(1)? != 0,
+                            stmt.read::<i64, _>(2)? != 0,
+                            stmt.read::<i64, _>(3)? != 0,
+                        ),
+                        sqlite::State::Done => return Ok(InviteRedemption::Invalid),
+                    }
+                };
+                if expired {
+                    return Ok(InviteRedemption::Invalid);
+                }
+                if revoked {
+                    return Ok(InviteRedemption::Revoked);
+                }
+
+                let already_member = {
+                    let mut stmt = conn.prepare("SELECT 1 FROM ChatMembers WHERE userID = ? AND chatID = ?;")?;
+                    stmt.bind((1, user_id))?;
+                    stmt.bind((2, chat_id))?;
+                    matches!(stmt.next()?, sqlite::State::Row)
+                };
+                if already_member {
+                    return Ok(InviteRedemption::AlreadyMember(chat_id));
+                }
+                if exhausted {
+                    return Ok(InviteRedemption::Exhausted);
+                }
+
+                {
+                    let mut stmt = conn.prepare("UPDATE InviteCodes SET use_count = use_count + 1 WHERE code = ?;")?;
+                    stmt.bind((1, code.as_str()))?;
+                    stmt.next()?;
+                }
+                // Existing history starts out read for new members
+                let mut stmt = conn.prepare(
+                    "INSERT INTO ChatMembers (chatID, userID, last_read_message_id)
+                                VALUES (?, ?, (SELECT COALESCE(MAX(messageID), 0) FROM Messages WHERE chatID = ?));"
+                )?;
+                stmt.bind((1, chat_id))?;
+                stmt.bind((2, user_id))?;
+                stmt.bind((3, chat_id))?;
+                stmt.next()?;
+                Ok(InviteRedemption::Joined(chat_id))
+            })
+        }).await
+    }
+
+    /// Creates an invite valid for `ttl_seconds`, optionally limited to `max_uses` redemptions.
+    pub async fn create_invite_code(&self, chat_id: i64, code: &str, creator_id: i64, max_uses: Option<i64>, ttl_seconds: i64) -> Result<(), sqlite::Error> {
+        let code = code.to_string();
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "INSERT INTO InviteCodes (code, chatID, creatorID, max_uses, expires_at)
+                            VALUES (?, ?, ?, ?, datetime('now', '+' || ? || ' seconds'));"
+            )?;
+            stmt.bind((1, code.as_str()))?;
+            stmt.bind((2, chat_id))?;
+            stmt.bind((3, creator_id))?;
+            stmt.bind((4, max_uses))?;
+            stmt.bind((5, ttl_seconds))?;
+            stmt.next()?;
+            Ok(())
+        }).await
+    }
+
+    /// Invites of a chat that are neither expired nor revoked, newest first.
+    pub async fn get_chat_invites(&self, chat_id: i64) -> Result<Vec<InviteView>, sqlite::Error> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT i.code, u.username, i.max_uses, i.use_count,
+                                strftime('%Y-%m-%dT%H:%M:%SZ', i.created_at), strftime('%Y-%m-%dT%H:%M:%SZ', i.expires_at)
+                            FROM InviteCodes AS i
+                            LEFT JOIN Users AS u ON u.userID = i.creatorID
+                            WHERE i.chatID = ? AND i.revoked_at IS NULL AND i.expires_at > datetime('now')
+                            ORDER BY i.created_at DESC;"
+            )?;
+            stmt.bind((1, chat_id))?;
+
+            let mut invites = Vec::new();
+            while let sqlite::State::Row = stmt.next()? {
+                invites.push(InviteView {
+                    code: stmt.read(0)?,
+                    creator: stmt.read(1)?,
+                    max_uses: stmt.read(2)?,
+                    use_count: stmt.read(3)?,
+                    created_at: stmt.read(4)?,
+                    expires_at: stmt.read(5)?,
+                });
+            }
+            Ok(invites)
+        }).await
+    }
+
+    /// Revokes an invite of `chat_id`. Returns false if there was no such live invite.
+    pub async fn revoke_invite_code(&self, chat_id: i64, code: &str) -> Result<bool, sqlite::Error> {
+        let code = code.to_string();
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "UPDATE InviteCodes SET revoked_at = CURRENT_TIMESTAMP
+                            WHERE code = ? AND chatID = ? AND revoked_at IS NULL
+                            RETURNING code;"
+            )?;
+            stmt.bind((1, code.as_str()))?;
+            stmt.bind((2, chat_id))?;
+            Ok(matches!(stmt.next()?, sqlite::State::Row))
+        }).await
+    }
+}
+
+fn query_messages(conn: &sqlite::Connection, chat_id: i64, condition: &str, order: &str, anchor: i64, limit: i64) -> Result<Vec<MessageView>, sqlite::Error> {
+    let mut stmt = conn.prepare(format!(
+        "SELECT {}
+                    FROM Messages AS m
+                    WHERE m.chatID = ? AND {}
+                    ORDER BY m.messageID {} LIMIT ?;",
+        MESSAGE_VIEW_COLUMNS, condition, order
+    ))?;
+    stmt.bind((1, chat_id))?;
+    stmt.bind((2, anchor))?;
+    stmt.bind((3, limit))?;
+    read_messages(&mut stmt)
+}
+
+/// Columns `read_messages` expects, from `Messages AS m`.
+const MESSAGE_VIEW_COLUMNS: &str = "m.messageID, m.username, m.message_text, strftime('%Y-%m-%dT%H:%M:%SZ', m.timestamp),
+                        strftime('%Y-%m-%dT%H:%M:%SZ', m.edited_at), m.deleted_at IS NOT NULL";
+
+fn read_messages(stmt: &mut sqlite::Statement) -> Result<Vec<MessageView>, sqlite::Error> {
+    let mut messages = Vec::new();
+    while let sqlite::State::Row = stmt.next()? {
+        let id: i64 = stmt.read(0)?;
+        let username: String = stmt.read(1)?;
+        let message_text: String = stmt.read(2)?;
+        let sent_at: String = stmt.read(3)?;
+        let edited_at: Option<String> = stmt.read(4)?;
+        let deleted = stmt.read::<i64, _>(5)? != 0;
+        messages.push(MessageView { id, username, text: message_text, sent_at, edited_at, deleted });
+    }
+    Ok(messages)
+}
+
+/// Runs `body` inside a transaction on `conn`, rolling back if it fails.
+pub fn transaction<T>(conn: &sqlite::Connection, body: impl FnOnce() -> Result<T, sqlite::Error>) -> Result<T, sqlite::Error> {
+    conn.execute("BEGIN IMMEDIATE;")?;
+    match body() {
+        Ok(value) => {
+            conn.execute("COMMIT;")?;
+            Ok(value)
         }
-        Ok(chat_id)
-    }
-
-    pub fn add_user_to_chat(&self, user_id: i64, chat_id: i64) -> Result<(), sqlite::Error> {
-        let conn = self.connection.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "INSERT INTO ChatMembers (chatID, userID) VALUES (?, ?);"
-        )?;
-        stmt.bind((1, chat_id))?;
-        stmt.bind((2, user_id))?;
-        stmt.next()?;
-        Ok(())
-    }
-
-    pub fn get_chat_id_by_invite_code(&self, code: &str) -> Result<Option<i64>, sqlite::Error> {
-        let conn = self.connection.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT chatID FROM InviteCodes WHERE code = ? AND expires_at > datetime('now');"
-        )?;
-        stmt.bind((1, code))?;
-        if let sqlite::State::Row = stmt.next()? {
-            let chat_id: i64 = stmt.read(0)?;
-            Ok(Some(chat_id))
-        } else {
-            Ok(None)
+        Err(e) => {
+            let _ = conn.execute("ROLLBACK;");
+            Err(e)
         }
     }
+}
 
-    pub fn create_invite_code(&self, chat_id: i64, code: &str) -> Result<(), sqlite::Error> {
-        let conn = self.connection.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "INSERT INTO InviteCodes (code, chatID, expires_at) VALUES (?, ?, datetime('now', '+7 days'));"
-        )?;
-        stmt.bind((1, code))?;
-        stmt.bind((2, chat_id))?;
-        stmt.next()?;
-        Ok(())
-    }
-}
\ No newline at end of file
+/// Returns true if the error was caused by a UNIQUE/PRIMARY KEY/etc. constraint.
+pub fn is_constraint_violation(e: &sqlite::Error) -> bool {
+    // SQLITE_CONSTRAINT
+    e.code == Some(19)
+}
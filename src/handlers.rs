@@ -1,24 +1,36 @@
-use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::http::{header, HeaderMap, StatusCode};
 use axum::response::{Html, IntoResponse, Redirect, Response};
-use axum::extract::{State, Path, Json};
+use axum::extract::{State, Path, Json, Query};
+use axum::extract::rejection::JsonRejection;
 use axum::Form;
 use crate::AppState;
-use crate::auth::AuthenticatedUser;
-use sha2::{Sha256, Digest};
+use crate::auth::{is_allowed_origin, AuthenticatedUser, ClientIp, session_cookie, hash_password, verify_password, verify_dummy_password, PasswordCheck, canonical_username, validate_username};
+use crate::database::{is_constraint_violation, ChatRole, InviteRedemption, MessageCursor, SearchFilters};
+use crate::template::MessageView;
+use crate::protocol::{CLOSE_CHAT_DELETED, CLOSE_MEMBERSHIP_ENDED, CLOSE_SESSION_REVOKED};
+use crate::config::HISTORY_MAX_PAGE_SIZE;
+use crate::ratelimit::{too_many_requests, Action, Key};
 use uuid::Uuid;
 use askama::Template;
 use serde_json::json;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
 
 #[derive(serde::Serialize)]
 pub struct StatusResponse {
     connected_clients: usize,
+    // Messages discarded for slow clients under the drop_oldest policy
+    dropped_messages: u64,
+    // Clients disconnected for being too slow under the disconnect policy
+    slow_disconnects: u64,
 }
 
 pub async fn index(State(state): State<AppState>, user: AuthenticatedUser) -> Response {
-    let chats = state.db_action().get_user_chats(user.user_id).unwrap_or_default();
+    let chats = state.db_action().get_user_chats(user.user_id).await.unwrap_or_default();
     let template = crate::template::IndexTemplate {
         username: &user.username,
-        chats: chats.into_iter().map(|(id, name)| crate::template::ChatView { id, name }).collect(),
+        csrf_token: &user.csrf_token,
+        chats: chats.into_iter().map(|(id, name, unread)| crate::template::ChatView { id, name, unread }).collect(),
     };
     match template.render() {
         Ok(body) => Html(body).into_response(),
@@ -33,7 +45,7 @@ pub struct NewChatPayload {
 
 pub async fn newchat(State(state): State<AppState>, user: AuthenticatedUser, Json(payload): Json<NewChatPayload>) -> Response {
     println!("Creating new chat: {} for user: {}", payload.chat_name, user.username);
-    match state.db_action().create_chat(&payload.chat_name, user.user_id) {
+    match state.db_action().create_chat(&payload.chat_name, user.user_id).await {
         Ok(id) => println!("Created new chat with id: {}", id),
         Err(e) => {
             eprintln!("Error creating chat: {}", e);
@@ -44,17 +56,24 @@ pub async fn newchat(State(state): State<AppState>, user: AuthenticatedUser, Jso
 }
 
 pub async fn chat(State(state): State<AppState>, Path(chat_id): Path<i64>, user: AuthenticatedUser) -> Response {
-    if state.db_action().check_chat_membership(user.user_id, chat_id).unwrap_or(false) == false {
-        return (StatusCode::FORBIDDEN, "You are not a member of this chat").into_response();
-    }
+    let role = match require_role(&state, user.user_id, chat_id, ChatRole::Member).await {
+        Ok(role) => role,
+        Err(error) => return error.into_response(),
+    };
 
-    let mut msgs = state.db_action().get_messages(chat_id, 50).unwrap_or_default();
-    let chats = state.db_action().get_user_chats(user.user_id).unwrap();
-    msgs.reverse();
+    // Taken before the history is read, so the socket replays any edit or delete the page misses
+    let synced_at = state.db_action().current_time().await.ok();
+    let page = load_message_page(&state, chat_id, MessageCursor::Latest, state.config.history_page_size).await.unwrap_or_default();
+    let chats = state.db_action().get_user_chats(user.user_id).await.unwrap();
     let template = crate::template::ChatTemplate {
         username: &user.username,
-        messages: msgs,
-        chats: chats.into_iter().map(|(id, name)| crate::template::ChatView { id, name }).collect(),
+        csrf_token: &user.csrf_token,
+        messages: page.messages,
+        older_cursor: page.older_cursor,
+        synced_at,
+        can_invite: role >= ChatRole::Admin,
+        max_message_chars: state.config.max_message_chars,
+        chats: chats.into_iter().map(|(id, name, unread)| crate::template::ChatView { id, name, unread }).collect(),
     };
     match template.render() {
         Ok(body) => Html(body).into_response(),
@@ -62,45 +81,278 @@ pub async fn chat(State(state): State<AppState>, Path(chat_id): Path<i64>, user:
     }
 }
 
+#[derive(serde::Deserialize)]
+pub struct HistoryQuery {
+    before: Option<i64>,
+    after: Option<i64>,
+    around: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(serde::Serialize, Default)]
+pub struct MessagePage {
+    messages: Vec<MessageView>, // oldest first
+    // Pass as `before=` to load the previous page, None if there is nothing older
+    older_cursor: Option<i64>,
+    // Pass as `after=` to load the next page, None if there is nothing newer
+    newer_cursor: Option<i64>,
+}
+
+async fn load_message_page(state: &AppState, chat_id: i64, cursor: MessageCursor, limit: i64) -> Result<MessagePage, sqlite::Error> {
+    let db = state.db_action();
+    let messages = db.get_messages(chat_id, cursor, limit).await?;
+    let older_cursor = match messages.first() {
+        Some(m) if db.has_messages_beyond(chat_id, m.id, true).await? => Some(m.id),
+        _ => None,
+    };
+    let newer_cursor = match messages.last() {
+        Some(m) if db.has_messages_beyond(chat_id, m.id, false).await? => Some(m.id),
+        _ => None,
+    };
+    Ok(MessagePage { messages, older_cursor, newer_cursor })
+}
+
+pub async fn chat_messages(
+    State(state): State<AppState>,
+    Path(chat_id): Path<i64>,
+    Query(query): Query<HistoryQuery>,
+    user: AuthenticatedUser
+) -> Response {
+    if !state.db_action().check_chat_membership(user.user_id, chat_id).await.unwrap_or(false) {
+        return (StatusCode::FORBIDDEN, "You are not a member of this chat").into_response();
+    }
+
+    let cursor = match (query.before, query.after, query.around) {
+        (None, None, None) => MessageCursor::Latest,
+        (Some(id), None, None) => MessageCursor::Before(id),
+        (None, Some(id), None) => MessageCursor::After(id),
+        (None, None, Some(id)) => MessageCursor::Around(id),
+        _ => return (StatusCode::BAD_REQUEST, "Use at most one of before, after and around").into_response(),
+    };
+    let limit = query.limit.unwrap_or(state.config.history_page_size);
+    if !(1..=HISTORY_MAX_PAGE_SIZE).contains(&limit) {
+        return (StatusCode::BAD_REQUEST, format!("limit must be between 1 and {}", HISTORY_MAX_PAGE_SIZE)).into_response();
+    }
+
+    match load_message_page(&state, chat_id, cursor, limit).await {
+        Ok(page) => Json(page).into_response(),
+        Err(e) => {
+            eprintln!("Error loading messages: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load messages").into_response()
+        }
+    }
+}
+
+const SEARCH_DEFAULT_LIMIT: i64 = 20;
+const SEARCH_MAX_LIMIT: i64 = 100;
+
+#[derive(serde::Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    chat_id: Option<i64>,
+    author: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    limit: Option<i64>,
+}
+
+#[derive(serde::Serialize)]
+pub struct SearchResultView {
+    id: i64,
+    chat_id: i64,
+    chat_name: String,
+    author: String,
+    sent_at: String,
+    // HTML-escaped, with matches wrapped in <mark>
+    snippet_html: String,
+}
+
+pub async fn search(State(state): State<AppState>, Query(query): Query<SearchQuery>, user: AuthenticatedUser) -> Response {
+    let Some(fts_query) = to_fts_query(&query.q) else {
+        return (StatusCode::BAD_REQUEST, "Search query must contain at least one word").into_response();
+    };
+    let limit = query.limit.unwrap_or(SEARCH_DEFAULT_LIMIT);
+    if !(1..=SEARCH_MAX_LIMIT).contains(&limit) {
+        return (StatusCode::BAD_REQUEST, format!("limit must be between 1 and {}", SEARCH_MAX_LIMIT)).into_response();
+    }
+    for date in [&query.from, &query.to].into_iter().flatten() {
+        if !is_iso_date(date) {
+            return (StatusCode::BAD_REQUEST, "from and to must be dates in YYYY-MM-DD format").into_response();
+        }
+    }
+
+    let filters = SearchFilters {
+        chat_id: query.chat_id,
+        author: query.author.filter(|a| !a.trim().is_empty()).map(|a| canonical_username(&a)),
+        from: query.from,
+        to: query.to,
+    };
+    match state.db_action().search_messages(user.user_id, &fts_query, &filters, limit).await {
+        Ok(hits) => Json(json!({
+            "results": hits.into_iter().map(|hit| SearchResultView {
+                id: hit.id,
+                chat_id: hit.chat_id,
+                chat_name: hit.chat_name,
+                author: hit.author,
+                sent_at: hit.sent_at,
+                snippet_html: escape_html(&hit.snippet).replace('\u{E000}', "<mark>").replace('\u{E001}', "</mark>"),
+            }).collect::<Vec<_>>()
+        })).into_response(),
+        Err(e) => {
+            eprintln!("Error searching messages: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to search messages").into_response()
+        }
+    }
+}
+
+/// Turns free text into an FTS5 query that matches messages containing every
+/// word, the last one as a prefix. Each word is quoted so FTS5 operators and
+/// punctuation in user input are taken literally.
+fn to_fts_query(input: &str) -> Option<String> {
+    let words: Vec<String> = input
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect();
+    if words.is_empty() {
+        return None;
+    }
+    Some(format!("{}*", words.join(" ")))
+}
+
+fn is_iso_date(value: &str) -> bool {
+    let bytes = value.as_bytes();
+    bytes.len() == 10
+        && bytes.iter().enumerate().all(|(i, b)| if i == 4 || i == 7 { *b == b'-' } else { b.is_ascii_digit() })
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[derive(serde::Serialize)]
+pub struct MessageEditView {
+    text: String,
+    replaced_at: String,
+}
+
+pub async fn message_edits(
+    State(state): State<AppState>,
+    Path((chat_id, message_id)): Path<(i64, i64)>,
+    user: AuthenticatedUser
+) -> Response {
+    if !state.db_action().check_chat_membership(user.user_id, chat_id).await.unwrap_or(false) {
+        return (StatusCode::FORBIDDEN, "You are not a member of this chat").into_response();
+    }
+    // Make sure the message belongs to this chat before exposing its history
+    match state.db_action().get_message_author(message_id, chat_id).await {
+        Ok(Some(_)) => (),
+        Ok(None) => return (StatusCode::NOT_FOUND, "Message not found").into_response(),
+        Err(e) => {
+            eprintln!("Error looking up message: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load edit history").into_response();
+        }
+    }
+
+    match state.db_action().get_message_edits(message_id).await {
+        Ok(edits) => Json(edits.into_iter().map(|(text, replaced_at)| MessageEditView { text, replaced_at }).collect::<Vec<_>>()).into_response(),
+        Err(e) => {
+            eprintln!("Error loading edit history: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load edit history").into_response()
+        }
+    }
+}
+
+/// Asks before joining: a GET can be triggered by any site the user visits, so
+/// the invite is only redeemed by the CSRF-checked POST the page sends.
 pub async fn invite(State(state): State<AppState>, Path(code): Path<String>, user: AuthenticatedUser) -> Response {
-    match state.db_action().get_chat_id_by_invite_code(&code) {
-        Ok(Some(chat_id)) => {
-            // Check if user is already a member
-            match state.db_action().check_chat_membership(user.user_id, chat_id) {
-                Ok(true) => return Redirect::to(&format!("/chat/{}", chat_id)).into_response(),
-                Ok(false) => (),
-                Err(e) => {
-                    eprintln!("Error checking chat membership: {}", e);
-                    return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to process invite").into_response();
-                }
-            }
-            // Add user to chat
-            match state.db_action().add_user_to_chat(user.user_id, chat_id) {
-                Ok(_) => Redirect::to(&format!("/chat/{}", chat_id)).into_response(),
-                Err(e) => {
-                    eprintln!("Error adding user to chat: {}", e);
-                    (StatusCode::INTERNAL_SERVER_ERROR, "Failed to join chat").into_response()
-                }
-            }
-        },
-        Ok(None) => (StatusCode::NOT_FOUND, "Invalid invite code").into_response(),
+    let (chat_id, chat_name, live) = match state.db_action().get_invite_chat(&code).await {
+        Ok(Some(chat)) => chat,
+        Ok(None) => return (StatusCode::NOT_FOUND, "Invalid invite code").into_response(),
         Err(e) => {
-            eprintln!("Error retrieving chat by invite code: {}", e);
+            eprintln!("Error looking up invite code: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to process invite").into_response();
+        }
+    };
+    if state.db_action().check_chat_membership(user.user_id, chat_id).await.unwrap_or(false) {
+        return Redirect::to(&format!("/chat/{}", chat_id)).into_response();
+    }
+    if !live {
+        return (StatusCode::GONE, "This invite has expired, been revoked or reached its usage limit").into_response();
+    }
+    let template = crate::template::InviteTemplate {
+        username: &user.username,
+        csrf_token: &user.csrf_token,
+        chat_name: &chat_name,
+    };
+    match template.render() {
+        Ok(body) => Html(body).into_response(),
+        Err(_e) => (StatusCode::INTERNAL_SERVER_ERROR, "Template render error").into_response(),
+    }
+}
+
+pub async fn redeem_invite(State(state): State<AppState>, Path(code): Path<String>, user: AuthenticatedUser, ClientIp(ip): ClientIp) -> Response {
+    if let Err(wait) = state.rate_limiter.check(Action::RedeemInvite, &[Key::User(user.user_id), Key::Ip(ip)]) {
+        return too_many_requests(wait);
+    }
+    match state.db_action().redeem_invite_code(&code, user.user_id).await {
+        Ok(InviteRedemption::Joined(chat_id)) | Ok(InviteRedemption::AlreadyMember(chat_id)) => {
+            Json(json!({ "chat_id": chat_id })).into_response()
+        }
+        Ok(InviteRedemption::Invalid) => (StatusCode::NOT_FOUND, "Invalid invite code").into_response(),
+        Ok(InviteRedemption::Revoked) => (StatusCode::GONE, "This invite has been revoked").into_response(),
+        Ok(InviteRedemption::Exhausted) => (StatusCode::GONE, "This invite has reached its usage limit").into_response(),
+        Err(e) => {
+            eprintln!("Error redeeming invite code: {}", e);
             (StatusCode::INTERNAL_SERVER_ERROR, "Failed to process invite").into_response()
         }
     }
 }
 
-pub async fn create_invite(State(state): State<AppState>, Path(chat_id): Path<i64>, user: AuthenticatedUser) -> Response {
-    // Check if user is a member of the chat
-    if state.db_action().check_chat_membership(user.user_id, chat_id).unwrap_or(false) == false {
-        return (StatusCode::FORBIDDEN, "You are not a member of this chat").into_response();
+#[derive(serde::Deserialize, Default)]
+pub struct CreateInvitePayload {
+    pub max_uses: Option<i64>,
+    pub expires_in_hours: Option<i64>,
+}
+
+pub async fn create_invite(
+    State(state): State<AppState>,
+    Path(chat_id): Path<i64>,
+    user: AuthenticatedUser,
+    payload: Result<Json<CreateInvitePayload>, JsonRejection>
+) -> Response {
+    if let Err(error) = require_role(&state, user.user_id, chat_id, ChatRole::Admin).await {
+        return error.into_response();
+    }
+
+    // The body is optional, a bare POST creates an invite with the defaults
+    let payload = match payload {
+        Ok(Json(payload)) => payload,
+        Err(JsonRejection::MissingJsonContentType(_)) => CreateInvitePayload::default(),
+        Err(rejection) => return rejection.into_response(),
+    };
+    if payload.max_uses.is_some_and(|uses| uses < 1) {
+        return (StatusCode::BAD_REQUEST, "max_uses must be at least 1").into_response();
+    }
+    let ttl_hours = payload.expires_in_hours.unwrap_or(state.config.invite_ttl_hours);
+    if !(1..=state.config.invite_max_ttl_hours).contains(&ttl_hours) {
+        return (StatusCode::BAD_REQUEST, format!("expires_in_hours must be between 1 and {}", state.config.invite_max_ttl_hours)).into_response();
     }
 
     // Generate invite code
     let invite_code = Uuid::new_v4().to_string();
 
-    match state.db_action().create_invite_code(chat_id, &invite_code) {
+    match state.db_action().create_invite_code(chat_id, &invite_code, user.user_id, payload.max_uses, ttl_hours * 3600).await {
         Ok(_) => {
             (StatusCode::CREATED, Json(json!({ "code": invite_code }))).into_response()
         },
@@ -111,9 +363,274 @@ pub async fn create_invite(State(state): State<AppState>, Path(chat_id): Path<i6
     }
 }
 
+pub async fn chat_invites(State(state): State<AppState>, Path(chat_id): Path<i64>, user: AuthenticatedUser) -> Response {
+    if let Err(error) = require_role(&state, user.user_id, chat_id, ChatRole::Admin).await {
+        return error.into_response();
+    }
+    match state.db_action().get_chat_invites(chat_id).await {
+        Ok(invites) => Json(invites).into_response(),
+        Err(e) => {
+            eprintln!("Error loading invites: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load invites").into_response()
+        }
+    }
+}
+
+pub async fn revoke_invite(
+    State(state): State<AppState>,
+    Path((chat_id, code)): Path<(i64, String)>,
+    user: AuthenticatedUser
+) -> Response {
+    if let Err(error) = require_role(&state, user.user_id, chat_id, ChatRole::Admin).await {
+        return error.into_response();
+    }
+    match state.db_action().revoke_invite_code(chat_id, &code).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "Invite not found").into_response(),
+        Err(e) => {
+            eprintln!("Error revoking invite: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to revoke invite").into_response()
+        }
+    }
+}
+
+/// Checks that the user is a member of the chat with at least `min` role and
+/// returns their actual role, or the error to respond with.
+async fn require_role(state: &AppState, user_id: i64, chat_id: i64, min: ChatRole) -> Result<ChatRole, (StatusCode, &'static str)> {
+    match state.db_action().get_member_role(user_id, chat_id).await {
+        Ok(Some(role)) if role >= min => Ok(role),
+        Ok(Some(_)) if min == ChatRole::Owner => Err((StatusCode::FORBIDDEN, "Only the chat owner can do this")),
+        Ok(Some(_)) => Err((StatusCode::FORBIDDEN, "Only the chat owner or admins can do this")),
+        Ok(None) => Err((StatusCode::FORBIDDEN, "You are not a member of this chat")),
+        Err(e) => {
+            eprintln!("Error checking chat role: {}", e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to check permissions"))
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct MemberView {
+    user_id: i64,
+    username: String,
+    role: ChatRole,
+    last_read_message_id: i64,
+}
+
+pub async fn chat_members(State(state): State<AppState>, Path(chat_id): Path<i64>, user: AuthenticatedUser) -> Response {
+    if let Err(error) = require_role(&state, user.user_id, chat_id, ChatRole::Member).await {
+        return error.into_response();
+    }
+    match state.db_action().get_chat_members(chat_id).await {
+        Ok(members) => Json(members.into_iter().map(|(user_id, username, role, last_read_message_id)| MemberView { user_id, username, role, last_read_message_id }).collect::<Vec<_>>()).into_response(),
+        Err(e) => {
+            eprintln!("Error loading chat members: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load members").into_response()
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct MarkReadPayload {
+    pub message_id: i64,
+}
+
+pub async fn mark_read(
+    State(state): State<AppState>,
+    Path(chat_id): Path<i64>,
+    user: AuthenticatedUser,
+    Json(payload): Json<MarkReadPayload>
+) -> Response {
+    if let Err(error) = require_role(&state, user.user_id, chat_id, ChatRole::Member).await {
+        return error.into_response();
+    }
+    match crate::websocket::mark_read(&state, chat_id, user.user_id, &user.username, payload.message_id).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            eprintln!("Error advancing read marker: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to mark messages as read").into_response()
+        }
+    }
+}
+
+pub async fn chat_online(State(state): State<AppState>, Path(chat_id): Path<i64>, user: AuthenticatedUser) -> Response {
+    if let Err(error) = require_role(&state, user.user_id, chat_id, ChatRole::Member).await {
+        return error.into_response();
+    }
+    Json(state.hub.online_users(chat_id)).into_response()
+}
+
+#[derive(serde::Deserialize)]
+pub struct RenameChatPayload {
+    pub chat_name: String,
+}
+
+pub async fn rename_chat(
+    State(state): State<AppState>,
+    Path(chat_id): Path<i64>,
+    user: AuthenticatedUser,
+    Json(payload): Json<RenameChatPayload>
+) -> Response {
+    if let Err(error) = require_role(&state, user.user_id, chat_id, ChatRole::Admin).await {
+        return error.into_response();
+    }
+    let chat_name = payload.chat_name.trim();
+    if chat_name.is_empty() {
+        return (StatusCode::BAD_REQUEST, "Chat name must not be empty").into_response();
+    }
+    match state.db_action().rename_chat(chat_id, chat_name).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            eprintln!("Error renaming chat: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to rename chat").into_response()
+        }
+    }
+}
+
+pub async fn delete_chat(State(state): State<AppState>, Path(chat_id): Path<i64>, user: AuthenticatedUser) -> Response {
+    if let Err(error) = require_role(&state, user.user_id, chat_id, ChatRole::Owner).await {
+        return error.into_response();
+    }
+    match state.db_action().delete_chat(chat_id).await {
+        Ok(_) => {
+            state.hub.close(chat_id, None, CLOSE_CHAT_DELETED, "This chat was deleted");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            eprintln!("Error deleting chat: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to delete chat").into_response()
+        }
+    }
+}
+
+pub async fn remove_member(
+    State(state): State<AppState>,
+    Path((chat_id, member_id)): Path<(i64, i64)>,
+    user: AuthenticatedUser
+) -> Response {
+    let role = match require_role(&state, user.user_id, chat_id, ChatRole::Admin).await {
+        Ok(role) => role,
+        Err(error) => return error.into_response(),
+    };
+    if member_id == user.user_id {
+        return (StatusCode::BAD_REQUEST, "Use the leave endpoint to remove yourself").into_response();
+    }
+    // Admins can only remove plain members, the owner can remove anyone
+    match state.db_action().get_member_role(member_id, chat_id).await {
+        Ok(Some(target)) if target < role => (),
+        Ok(Some(_)) => return (StatusCode::FORBIDDEN, "You cannot remove a member with an equal or higher role").into_response(),
+        Ok(None) => return (StatusCode::NOT_FOUND, "User is not a member of this chat").into_response(),
+        Err(e) => {
+            eprintln!("Error checking chat role: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to remove member").into_response();
+        }
+    }
+    match state.db_action().remove_chat_member(member_id, chat_id).await {
+        Ok(_) => {
+            state.hub.close(chat_id, Some(member_id), CLOSE_MEMBERSHIP_ENDED, "You were removed from this chat");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            eprintln!("Error removing chat member: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to remove member").into_response()
+        }
+    }
+}
+
+pub async fn leave_chat(State(state): State<AppState>, Path(chat_id): Path<i64>, user: AuthenticatedUser) -> Response {
+    let role = match require_role(&state, user.user_id, chat_id, ChatRole::Member).await {
+        Ok(role) => role,
+        Err(error) => return error.into_response(),
+    };
+
+    // The owner may only walk away from an otherwise empty chat, which then goes with them
+    if role == ChatRole::Owner {
+        match state.db_action().get_chat_members(chat_id).await {
+            Ok(members) if members.len() > 1 => {
+                return (StatusCode::CONFLICT, "Transfer ownership before leaving this chat").into_response();
+            }
+            Ok(_) => return delete_chat(State(state), Path(chat_id), user).await,
+            Err(e) => {
+                eprintln!("Error loading chat members: {}", e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to leave chat").into_response();
+            }
+        }
+    }
+
+    match state.db_action().remove_chat_member(user.user_id, chat_id).await {
+        Ok(_) => {
+            state.hub.close(chat_id, Some(user.user_id), CLOSE_MEMBERSHIP_ENDED, "You left this chat");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            eprintln!("Error leaving chat: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to leave chat").into_response()
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct SetRolePayload {
+    pub role: ChatRole,
+}
+
+pub async fn set_member_role(
+    State(state): State<AppState>,
+    Path((chat_id, member_id)): Path<(i64, i64)>,
+    user: AuthenticatedUser,
+    Json(payload): Json<SetRolePayload>
+) -> Response {
+    if let Err(error) = require_role(&state, user.user_id, chat_id, ChatRole::Owner).await {
+        return error.into_response();
+    }
+    if payload.role == ChatRole::Owner {
+        return (StatusCode::BAD_REQUEST, "Use the transfer endpoint to change the owner").into_response();
+    }
+    if member_id == user.user_id {
+        return (StatusCode::BAD_REQUEST, "Transfer ownership before changing your own role").into_response();
+    }
+    match state.db_action().set_member_role(member_id, chat_id, payload.role).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "User is not a member of this chat").into_response(),
+        Err(e) => {
+            eprintln!("Error setting chat role: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to change role").into_response()
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct TransferOwnershipPayload {
+    pub user_id: i64,
+}
+
+pub async fn transfer_ownership(
+    State(state): State<AppState>,
+    Path(chat_id): Path<i64>,
+    user: AuthenticatedUser,
+    Json(payload): Json<TransferOwnershipPayload>
+) -> Response {
+    if let Err(error) = require_role(&state, user.user_id, chat_id, ChatRole::Owner).await {
+        return error.into_response();
+    }
+    if payload.user_id == user.user_id {
+        return (StatusCode::BAD_REQUEST, "You already own this chat").into_response();
+    }
+    match state.db_action().transfer_ownership(chat_id, user.user_id, payload.user_id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "User is not a member of this chat").into_response(),
+        Err(e) => {
+            eprintln!("Error transferring ownership: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to transfer ownership").into_response()
+        }
+    }
+}
+
 pub async fn status(State(state): State<AppState>) -> Json<StatusResponse> {
     let response = StatusResponse {
         connected_clients: state.get_connected_clients(),
+        dropped_messages: state.outbox_stats.dropped_messages.load(Ordering::Relaxed),
+        slow_disconnects: state.outbox_stats.slow_disconnects.load(Ordering::Relaxed),
     };
     Json(response)
 }
@@ -130,90 +647,215 @@ pub struct AuthForm {
 
 pub async fn auth_post(
     State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
     Form(form): Form<AuthForm>
 ) -> Response {
+    // Login CSRF: the session cookie is being set, not sent, so SameSite doesn't help
+    if !is_allowed_origin(&headers, &state) {
+        return (StatusCode::FORBIDDEN, "Origin not allowed").into_response();
+    }
 
     // Extract username and password from the form
-    let username = form.username.as_str();
-    let password = form.password.as_str();
-    // Hash the password
-    let hash = format!("{:x}", Sha256::digest(password.as_bytes()));
-
-    {
-        match state.db_action().get_user(username) {
-            Ok(Some(_)) => {
-                // User exists, check password
-                if state.db_action().check_password(username, &hash) {
-                    return start_session(&state, &username)
-                } else {
-                    return Html("<p>Invalid password, perhaps user already exists, under a different password?
-                    <a href=\"/auth\">Try again</a>
-                    </p>".to_string()).into_response()
-                }
-            }
-            Ok(None) => {
-                // User not found, register new user
-                state.db_action().add_user(username, &hash).unwrap();
-                // Repeat the check to authorize the new user
-                if state.db_action().check_password(username, &hash) {
-                    return start_session(&state, &username)
-                } else {
-                    return Html("<p>Invalid password, perhaps user already exists, under a different password?
-                    <a href=\"/auth\">Try again</a>
-                    </p>".to_string()).into_response()
+    let username = canonical_username(&form.username);
+    let password = form.password;
+
+    if let Err(wait) = state.rate_limiter.check(Action::Login, &[Key::Ip(ip), Key::username(&username)]) {
+        return too_many_requests(wait);
+    }
+
+    match state.db_action().get_password_hash(&username).await {
+        Ok(Some((user_id, stored_hash))) => {
+            match verify_password(password.clone(), stored_hash).await {
+                PasswordCheck::Valid => start_session(&state, user_id, &headers, ip).await,
+                PasswordCheck::ValidLegacy => {
+                    // Upgrade the legacy SHA-256 hash now that we have the plaintext
+                    match hash_password(password).await {
+                        Ok(hash) => {
+                            if let Err(e) = state.db_action().update_password_hash(user_id, &hash).await {
+                                eprintln!("Error upgrading password hash: {}", e);
+                            }
+                        }
+                        Err(e) => eprintln!("Error hashing password: {}", e),
+                    }
+                    start_session(&state, user_id, &headers, ip).await
                 }
+                PasswordCheck::Invalid => invalid_credentials(),
             }
-            Err(e) => {
-                return Html(format!("<p>Error: {}</p>", e)).into_response()
-            }
+        }
+        // Unknown usernames get the same response as wrong passwords, after the same delay
+        Ok(None) => {
+            verify_dummy_password(password).await;
+            invalid_credentials()
+        }
+        Err(e) => {
+            Html(format!("<p>Error: {}</p>", e)).into_response()
         }
     }
 }
 
+fn invalid_credentials() -> Response {
+    (StatusCode::UNAUTHORIZED, Html("<p>Invalid username or password.
+    <a href=\"/auth\">Try again</a> or <a href=\"/register\">create an account</a>
+    </p>".to_string())).into_response()
+}
+
+pub async fn register_get() -> Html<&'static str> {
+    Html(std::include_str!("../templates/register.html"))
+}
+
+#[derive(serde::Deserialize)]
+pub struct RegisterForm {
+    username: String,
+    password: String,
+    password_confirm: String,
+}
+
+pub async fn register_post(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    Form(form): Form<RegisterForm>
+) -> Response {
+    // Login CSRF: the session cookie is being set, not sent, so SameSite doesn't help
+    if !is_allowed_origin(&headers, &state) {
+        return (StatusCode::FORBIDDEN, "Origin not allowed").into_response();
+    }
+    if let Err(wait) = state.rate_limiter.check(Action::Register, &[Key::Ip(ip)]) {
+        return too_many_requests(wait);
+    }
+    let username = match validate_username(&form.username) {
+        Ok(username) => username,
+        Err(e) => return registration_error(StatusCode::UNPROCESSABLE_ENTITY, &e.message()),
+    };
+    if form.password.is_empty() {
+        return registration_error(StatusCode::UNPROCESSABLE_ENTITY, "Password must not be empty");
+    }
+    if form.password != form.password_confirm {
+        return registration_error(StatusCode::UNPROCESSABLE_ENTITY, "Passwords do not match");
+    }
+
+    // Cheap pre-check so we don't spend an Argon2 hash on an obviously taken name
+    match state.db_action().get_user(&username).await {
+        Ok(Some(_)) => return registration_error(StatusCode::CONFLICT, "This username is already taken"),
+        Ok(None) => (),
+        Err(e) => {
+            eprintln!("Error looking up user: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to register user").into_response();
+        }
+    }
+
+    let hash = match hash_password(form.password).await {
+        Ok(hash) => hash,
+        Err(e) => {
+            eprintln!("Error hashing password: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to register user").into_response();
+        }
+    };
+
+    match state.db_action().add_user(&username, &hash).await {
+        Ok(user_id) => start_session(&state, user_id, &headers, ip).await,
+        // Lost a race against a concurrent registration of the same name
+        Err(e) if is_constraint_violation(&e) => {
+            registration_error(StatusCode::CONFLICT, "This username is already taken")
+        }
+        Err(e) => {
+            eprintln!("Error adding user: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to register user").into_response()
+        }
+    }
+}
+
+fn registration_error(status: StatusCode, message: &str) -> Response {
+    (status, Html(format!("<p>{}.
+    <a href=\"/register\">Try again</a>
+    </p>", message))).into_response()
+}
+
 fn generate_session_token() -> String {
     Uuid::new_v4().to_string()
 }
 
-fn start_session(state: &AppState, username: &str) -> Response {
+/// Longest User-Agent kept for the sessions page.
+const MAX_USER_AGENT_CHARS: usize = 512;
+
+async fn start_session(state: &AppState, user_id: i64, headers: &HeaderMap, ip: IpAddr) -> Response {
     let session_token = generate_session_token();
-                let user_id = match state.db_action().get_user(username).ok().and_then(|opt| opt.map(|(id, _)| id)) {
-                    Some(id) => id,
-                    None => return Html("<p>Invalid credentials</p>".to_string()).into_response(),
-                };
-                
-                if state.db_action().create_session(user_id, &session_token).is_ok() {
-                    let mut headers = HeaderMap::new();
-                    headers.insert(
-                        "Set-Cookie",
-                        HeaderValue::from_str(&format!("session_token={}; HttpOnly; Path=/", session_token)).unwrap()
-                    );
-                    return (headers, Redirect::to("/")).into_response();
-                }
-                Html("<p>Invalid credentials</p>".to_string()).into_response()
+    let csrf_token = generate_session_token();
+    let user_agent: Option<String> = headers
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(|ua| ua.chars().take(MAX_USER_AGENT_CHARS).collect());
+    let ip = ip.to_string();
+    if state.db_action().create_session(user_id, &session_token, &csrf_token, state.config.session_ttl_hours, user_agent.as_deref(), &ip).await.is_ok() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Set-Cookie", session_cookie(state, Some(&session_token)));
+        return (headers, Redirect::to("/")).into_response();
+    }
+    Html("<p>Invalid credentials</p>".to_string()).into_response()
 }
 
-pub async fn logout(
-    State(state): State<AppState>,
-    headers: HeaderMap
-) -> impl IntoResponse {
-    // Extract and delete session
-    if let Some(cookie_header) = headers.get("cookie") {
-        if let Ok(cookie_str) = cookie_header.to_str() {
-            for cookie in cookie_str.split(';') {
-                let cookie = cookie.trim();
-                if cookie.starts_with("session_token=") {
-                    let token = &cookie[14..];
-                    let _ = state.db_action().delete_session(token);
-                    break;
-                }
-            }
-        }
+pub async fn logout(State(state): State<AppState>, user: AuthenticatedUser) -> impl IntoResponse {
+    if let Err(e) = state.db_action().delete_session(user.user_id, user.session_id).await {
+        eprintln!("Error deleting session: {}", e);
     }
-    
+    // Other tabs of this browser would keep chatting on a dead session
+    state.hub.close_sessions(&[user.session_id], CLOSE_SESSION_REVOKED, "You have logged out");
+
     let mut headers = HeaderMap::new();
-    headers.insert(
-        "Set-Cookie",
-        HeaderValue::from_str("session_token=; HttpOnly; Path=/; Max-Age=0").unwrap()
-    );
+    headers.insert("Set-Cookie", session_cookie(&state, None));
     (headers, Redirect::to("/auth"))
-}
\ No newline at end of file
+}
+
+pub async fn sessions(State(state): State<AppState>, user: AuthenticatedUser) -> Response {
+    let sessions = match state.db_action().get_user_sessions(user.user_id).await {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            eprintln!("Error loading sessions: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load sessions").into_response();
+        }
+    };
+    let template = crate::template::SessionsTemplate {
+        username: &user.username,
+        csrf_token: &user.csrf_token,
+        current_session_id: user.session_id,
+        sessions,
+    };
+    match template.render() {
+        Ok(body) => Html(body).into_response(),
+        Err(_e) => (StatusCode::INTERNAL_SERVER_ERROR, "Template render error").into_response(),
+    }
+}
+
+pub async fn revoke_session(State(state): State<AppState>, Path(session_id): Path<i64>, user: AuthenticatedUser) -> Response {
+    match state.db_action().delete_session(user.user_id, session_id).await {
+        Ok(true) => (),
+        // Someone else's session looks the same as one that doesn't exist
+        Ok(false) => return (StatusCode::NOT_FOUND, "Session not found").into_response(),
+        Err(e) => {
+            eprintln!("Error deleting session: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to revoke session").into_response();
+        }
+    }
+    state.hub.close_sessions(&[session_id], CLOSE_SESSION_REVOKED, "This session was revoked");
+
+    if session_id == user.session_id {
+        let mut headers = HeaderMap::new();
+        headers.insert("Set-Cookie", session_cookie(&state, None));
+        return (StatusCode::NO_CONTENT, headers).into_response();
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+pub async fn revoke_other_sessions(State(state): State<AppState>, user: AuthenticatedUser) -> Response {
+    match state.db_action().delete_other_sessions(user.user_id, user.session_id).await {
+        Ok(ended) => {
+            state.hub.close_sessions(&ended, CLOSE_SESSION_REVOKED, "This session was revoked");
+            Json(json!({ "revoked": ended.len() })).into_response()
+        }
+        Err(e) => {
+            eprintln!("Error deleting sessions: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to revoke sessions").into_response()
+        }
+    }
+}
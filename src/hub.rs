@@ -0,0 +1,218 @@
+//! Registry of open chat sockets, grouped by chat.
+//!
+//! Each chat with at least one open socket has a `Room` holding the senders of
+//! its sockets. A broadcast only touches the room of its chat, so its cost
+//! depends on how many sockets that chat has, not on the whole server. Rooms
+//! are created by the first `join` and dropped when their last socket leaves.
+//!
+//! Locking: the room map lock is only held to find, create or drop a room.
+//! Sending happens under the room's own lock, which keeps every socket in a
+//! chat seeing events in the same order. When both are needed the map lock is
+//! taken first.
+
+use axum::extract::ws::{CloseFrame, Message};
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use crate::outbox::Outbox;
+use crate::protocol::{OnlineUser, ServerEvent, CURRENT_VERSION};
+
+pub struct SocketData {
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub username: String,
+    // The login session the socket was opened with, so revoking it can close the socket
+    pub session_id: i64,
+    pub version: u32,
+    pub socket: Arc<Outbox>,
+}
+
+#[derive(Default)]
+pub struct Hub {
+    rooms: Mutex<Rooms>,
+}
+
+#[derive(Default)]
+struct Rooms {
+    by_chat: HashMap<i64, Arc<Room>>,
+    // Set by `close_all`, after which nothing can join
+    closed: bool,
+}
+
+/// The sockets joined to one chat, by socket ID.
+#[derive(Default)]
+struct Room {
+    sockets: Mutex<HashMap<String, SocketData>>,
+}
+
+impl Hub {
+    /// Registers a socket in its chat and, if it is the user's first one there,
+    /// tells the rest of the chat with `presence_joined`. Returns false, without
+    /// registering, once the hub has been closed for shutdown.
+    pub fn join(&self, socket_id: &str, socket_data: SocketData) -> bool {
+        let mut rooms = self.rooms.lock().unwrap();
+        if rooms.closed {
+            return false;
+        }
+        let chat_id = socket_data.chat_id;
+        let room = rooms.by_chat.entry(chat_id).or_default();
+        let mut sockets = room.sockets.lock().unwrap();
+
+        let already_online = is_online(&sockets, socket_data.user_id);
+        let joined = ServerEvent::PresenceJoined {
+            chat_id,
+            user_id: socket_data.user_id,
+            username: socket_data.username.clone(),
+        };
+        sockets.insert(socket_id.to_string(), socket_data);
+        if !already_online {
+            send_to_room(&sockets, Some(socket_id), &joined);
+        }
+        true
+    }
+
+    /// Unregisters a socket, announcing `presence_left` if it was the user's last
+    /// one in the chat. Does nothing if the socket is already gone.
+    pub fn leave(&self, chat_id: i64, socket_id: &str) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(room) = rooms.by_chat.get(&chat_id).cloned() else {
+            return;
+        };
+        let mut sockets = room.sockets.lock().unwrap();
+        if let Some(departed) = sockets.remove(socket_id) {
+            announce_departure(&sockets, &departed);
+        }
+        if sockets.is_empty() {
+            rooms.by_chat.remove(&chat_id);
+        }
+    }
+
+    /// Sends `event` to every socket joined to `chat_id`, except `skip`.
+    pub fn broadcast(&self, chat_id: i64, skip: Option<&str>, event: &ServerEvent) {
+        let Some(room) = self.room(chat_id) else {
+            return;
+        };
+        let sockets = room.sockets.lock().unwrap();
+        send_to_room(&sockets, skip, event);
+    }
+
+    /// Users with at least one socket open in `chat_id`, each listed once, by name.
+    pub fn online_users(&self, chat_id: i64) -> Vec<OnlineUser> {
+        let Some(room) = self.room(chat_id) else {
+            return Vec::new();
+        };
+        let sockets = room.sockets.lock().unwrap();
+        let mut users: Vec<OnlineUser> = sockets
+            .values()
+            .map(|socket_data| (socket_data.user_id, socket_data.username.clone()))
+            .collect::<HashMap<_, _>>()
+            .into_iter()
+            .map(|(user_id, username)| OnlineUser { user_id, username })
+            .collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        users
+    }
+
+    /// Closes the sockets of `user_id` in `chat_id`, or of everyone in it if
+    /// `user_id` is None, and unregisters them so they stop receiving broadcasts.
+    pub fn close(&self, chat_id: i64, user_id: Option<i64>, code: u16, reason: &str) {
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.close_where(chat_id, |socket_data| user_id.is_none_or(|user_id| socket_data.user_id == user_id), code, reason);
+    }
+
+    /// Closes every socket opened with one of `session_ids`, in any chat.
+    pub fn close_sessions(&self, session_ids: &[i64], code: u16, reason: &str) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let chat_ids: Vec<i64> = rooms.by_chat.keys().copied().collect();
+        for chat_id in chat_ids {
+            rooms.close_where(chat_id, |socket_data| session_ids.contains(&socket_data.session_id), code, reason);
+        }
+    }
+
+    /// Closes every socket and stops accepting new ones.
+    pub fn close_all(&self, code: u16, reason: &str) {
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.closed = true;
+        for (_, room) in rooms.by_chat.drain() {
+            for (_, socket_data) in room.sockets.lock().unwrap().drain() {
+                send_close(&socket_data, code, reason);
+            }
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.rooms.lock().unwrap().closed
+    }
+
+    /// Number of open sockets across all chats.
+    pub fn connected_clients(&self) -> usize {
+        let rooms = self.rooms.lock().unwrap();
+        rooms.by_chat.values().map(|room| room.sockets.lock().unwrap().len()).sum()
+    }
+
+    fn room(&self, chat_id: i64) -> Option<Arc<Room>> {
+        self.rooms.lock().unwrap().by_chat.get(&chat_id).cloned()
+    }
+}
+
+impl Rooms {
+    fn close_where(&mut self, chat_id: i64, matches: impl Fn(&SocketData) -> bool, code: u16, reason: &str) {
+        let Some(room) = self.by_chat.get(&chat_id).cloned() else {
+            return;
+        };
+        let mut sockets = room.sockets.lock().unwrap();
+        let closing: Vec<String> = sockets
+            .iter()
+            .filter(|(_, socket_data)| matches(socket_data))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in closing {
+            if let Some(socket_data) = sockets.remove(&id) {
+                send_close(&socket_data, code, reason);
+                announce_departure(&sockets, &socket_data);
+            }
+        }
+        if sockets.is_empty() {
+            self.by_chat.remove(&chat_id);
+        }
+    }
+}
+
+fn is_online(sockets: &HashMap<String, SocketData>, user_id: i64) -> bool {
+    sockets.values().any(|socket_data| socket_data.user_id == user_id)
+}
+
+/// Broadcasts `presence_left` for a socket that was just removed from `sockets`,
+/// unless its user still has another socket open in the chat.
+fn announce_departure(sockets: &HashMap<String, SocketData>, departed: &SocketData) {
+    if is_online(sockets, departed.user_id) {
+        return;
+    }
+    let event = ServerEvent::PresenceLeft {
+        chat_id: departed.chat_id,
+        user_id: departed.user_id,
+        username: departed.username.clone(),
+    };
+    send_to_room(sockets, None, &event);
+}
+
+fn send_to_room(sockets: &HashMap<String, SocketData>, skip: Option<&str>, event: &ServerEvent) {
+    // Encode once per protocol version rather than once per socket
+    let frames: [OnceCell<Option<String>>; CURRENT_VERSION as usize + 1] = Default::default();
+    for (id, socket_data) in sockets.iter() {
+        if Some(id.as_str()) == skip {
+            continue;
+        }
+        let frame = frames[socket_data.version as usize].get_or_init(|| event.encode(socket_data.version));
+        if let Some(frame) = frame {
+            socket_data.socket.push(Message::Text(frame.clone()));
+        }
+    }
+}
+
+fn send_close(socket_data: &SocketData, code: u16, reason: &str) {
+    socket_data.socket.push(Message::Close(Some(CloseFrame {
+        code,
+        reason: reason.to_string().into(),
+    })));
+}
@@ -0,0 +1,138 @@
+//! Periodic removal of expired sessions and invite codes.
+//!
+//! Both are checked against `expires_at` wherever they are used, so an expired
+//! row is never read again, but nothing deleted it either. Every
+//! `janitor_interval_secs` the janitor deletes them, `janitor_batch_size` rows
+//! per statement, so the write lock is given up between batches and requests
+//! don't queue behind one large delete. Revoked and used-up invites keep their
+//! `expires_at` and go once it passes.
+
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, MissedTickBehavior};
+use crate::database::{Database, Expiring};
+
+#[derive(Default)]
+pub struct PurgeCounts {
+    pub sessions: usize,
+    pub invites: usize,
+}
+
+/// Deletes every expired row now. Also what `--purge-expired` runs.
+pub async fn purge_expired(db: &Database, batch_size: i64) -> Result<PurgeCounts, sqlite::Error> {
+    Ok(PurgeCounts {
+        sessions: purge_table(db, Expiring::Sessions, batch_size).await?,
+        invites: purge_table(db, Expiring::InviteCodes, batch_size).await?,
+    })
+}
+
+async fn purge_table(db: &Database, table: Expiring, batch_size: i64) -> Result<usize, sqlite::Error> {
+    let mut total = 0;
+    loop {
+        let deleted = db.purge_expired(table, batch_size).await?;
+        total += deleted;
+        // A short batch means nothing expired is left
+        if deleted < batch_size as usize {
+            return Ok(total);
+        }
+    }
+}
+
+/// Runs `purge_expired` every `period`, starting now, until the task is aborted.
+pub fn spawn(db: Database, period: Duration, batch_size: i64) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticks = interval(period);
+        // After a long stall one pass catches up on everything
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            match purge_expired(&db, batch_size).await {
+                Ok(counts) if counts.sessions > 0 || counts.invites > 0 => {
+                    println!("Purged {} expired sessions and {} expired invites", counts.sessions, counts.invites);
+                }
+                Ok(_) => (),
+                Err(e) => eprintln!("Error purging expired rows: {}", e),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    /// A migrated database in a temp file holding expired and live sessions
+    /// and invites, and a second connection to look at it directly.
+    async fn database() -> (Database, sqlite::Connection, PathBuf) {
+        let path = std::env::temp_dir().join(format!("janitor-{}.db", uuid::Uuid::new_v4()));
+        let db = Database::new(&path);
+        db.migrate().await.unwrap();
+        let conn = sqlite::open(&path).unwrap();
+        conn.execute(
+            "
+            INSERT INTO Users (userID, username, password_hash) VALUES (1, 'alice', 'hash');
+            INSERT INTO Chats (chatID, chat_name) VALUES (1, 'general');
+            INSERT INTO Sessions (userID, session_token, csrf_token, expires_at) VALUES
+                (1, 'expired1', 'csrf', datetime('now', '-1 hours')),
+                (1, 'live1', 'csrf', datetime('now', '+1 hours')),
+                (1, 'expired2', 'csrf', datetime('now', '-2 days')),
+                (1, 'expired3', 'csrf', datetime('now', '-1 seconds')),
+                (1, 'live2', 'csrf', datetime('now', '+30 days'));
+            INSERT INTO InviteCodes (code, chatID, creatorID, expires_at, revoked_at) VALUES
+                ('expired1', 1, 1, datetime('now', '-1 hours'), NULL),
+                ('live1', 1, 1, datetime('now', '+1 hours'), NULL),
+                ('revoked', 1, 1, datetime('now', '-1 hours'), datetime('now', '-2 hours')),
+                ('revoked_live', 1, 1, datetime('now', '+1 hours'), datetime('now'));
+            ",
+        )
+        .unwrap();
+        (db, conn, path)
+    }
+
+    fn remove(path: &Path) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    fn column(conn: &sqlite::Connection, sql: &str) -> Vec<String> {
+        let mut stmt = conn.prepare(sql).unwrap();
+        let mut values = Vec::new();
+        while let sqlite::State::Row = stmt.next().unwrap() {
+            values.push(stmt.read(0).unwrap());
+        }
+        values
+    }
+
+    #[tokio::test]
+    async fn purges_in_batches_and_keeps_live_rows() {
+        let (db, conn, path) = database().await;
+
+        // One row per batch: the loop runs until a batch comes back empty
+        let counts = purge_expired(&db, 1).await.unwrap();
+        assert_eq!((counts.sessions, counts.invites), (3, 2));
+        assert_eq!(column(&conn, "SELECT session_token FROM Sessions ORDER BY sessionID;"), ["live1", "live2"]);
+        assert_eq!(column(&conn, "SELECT code FROM InviteCodes ORDER BY code;"), ["live1", "revoked_live"]);
+
+        // Nothing left to do
+        let counts = purge_expired(&db, 1).await.unwrap();
+        assert_eq!((counts.sessions, counts.invites), (0, 0));
+        remove(&path);
+    }
+
+    #[tokio::test]
+    async fn stops_on_a_short_batch() {
+        let (db, conn, path) = database().await;
+
+        // A full batch of 2, then a short one of 1 ends the loop
+        assert_eq!(purge_table(&db, Expiring::Sessions, 2).await.unwrap(), 3);
+        // A batch larger than what expired is short straight away
+        conn.execute("INSERT INTO Sessions (userID, session_token, csrf_token, expires_at) VALUES (1, 'expired4', 'csrf', datetime('now', '-1 hours'));")
+            .unwrap();
+        assert_eq!(purge_table(&db, Expiring::Sessions, 500).await.unwrap(), 1);
+        assert_eq!(purge_table(&db, Expiring::InviteCodes, 500).await.unwrap(), 2);
+        assert_eq!(column(&conn, "SELECT session_token FROM Sessions ORDER BY sessionID;"), ["live1", "live2"]);
+        remove(&path);
+    }
+}
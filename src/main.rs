@@ -3,45 +3,91 @@ mod handlers;
 mod database;
 mod auth;
 mod template;
+mod protocol;
+mod migrations;
+mod config;
+mod hub;
+mod outbox;
+mod ratelimit;
+mod janitor;
 
 use axum::Router;
 use tower_http::services::ServeDir;
-use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
-use tokio::sync::mpsc;
-use axum::extract::ws::Message;
+use std::sync::Arc;
+use tokio::sync::watch;
 
 use websocket::{chatsocket_handler};
 use handlers::*;
 use database::Database;
-
-pub struct SocketData {
-    pub chat_id: i64,
-    pub socket: mpsc::UnboundedSender<Message>,
-}
+use protocol::CLOSE_SERVER_RESTARTING;
+use hub::Hub;
+use outbox::OutboxStats;
+use ratelimit::RateLimiter;
+use config::{Cli, Config};
+use clap::Parser;
 
 #[derive(Clone)]
 pub struct AppState {
-    sockets: Arc<Mutex<HashMap<String, SocketData>>>,
+    pub hub: Arc<Hub>,
+    pub outbox_stats: Arc<OutboxStats>,
+    pub rate_limiter: Arc<RateLimiter>,
     db: Database,
+    pub config: Arc<Config>,
+    // Number of WebSocket connection tasks still running, see `track_connection`
+    open_connections: Arc<watch::Sender<usize>>,
+}
+
+/// Held by a WebSocket connection task for as long as it runs.
+pub struct ConnectionGuard {
+    open_connections: Arc<watch::Sender<usize>>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.open_connections.send_modify(|count| *count -= 1);
+    }
 }
 
 impl AppState {
-    pub fn new() -> Self {
-        let database: Database = Database::new();
-        match database.create() {
-            Ok(_) => println!("Database schema created successfully."),
-            Err(e) => panic!("Error creating database schema: {}", e),
+    pub async fn new(config: Config) -> Self {
+        let database: Database = Database::new(&config.database_path);
+        match database.migrate().await {
+            Ok(_) => println!("Database schema is up to date."),
+            Err(e) => panic!("Error migrating database schema: {}", e),
         }
         AppState {
-            sockets: Arc::new(Mutex::new(HashMap::new())),
+            hub: Arc::new(Hub::default()),
+            outbox_stats: Arc::new(OutboxStats::default()),
+            rate_limiter: Arc::new(RateLimiter::new(config.rate_limits.clone())),
             db: database,
+            config: Arc::new(config),
+            open_connections: Arc::new(watch::Sender::new(0)),
         }
     }
 
+    /// Counts a WebSocket connection task until the returned guard is dropped,
+    /// so shutdown can wait for its in-flight work.
+    pub fn track_connection(&self) -> ConnectionGuard {
+        self.open_connections.send_modify(|count| *count += 1);
+        ConnectionGuard { open_connections: Arc::clone(&self.open_connections) }
+    }
+
+    /// Refuses new WebSockets and asks every open one to close.
+    fn begin_shutdown(&self) {
+        self.hub.close_all(CLOSE_SERVER_RESTARTING, "Server restarting");
+    }
+
+    /// Waits until every connection task has finished, or `timeout` passes.
+    /// Returns the number of tasks still running.
+    async fn drain_connections(&self, timeout: std::time::Duration) -> usize {
+        let mut open = self.open_connections.subscribe();
+        let _ = tokio::time::timeout(timeout, open.wait_for(|count| *count == 0)).await;
+        let remaining = *open.borrow();
+        remaining
+    }
+
     pub fn get_connected_clients(&self) -> usize {
-        let sockets = self.sockets.lock().unwrap();
-        sockets.len()
+        self.hub.connected_clients()
     }
 
     pub fn db_action(&self) -> Database {
@@ -49,22 +95,130 @@ impl AppState {
     }
 }
 
+/// `--pending-migrations`: lists what startup would apply, without touching the schema.
+async fn print_pending_migrations(config: &Config) {
+    match Database::new(&config.database_path).pending_migrations().await {
+        Ok(pending) if pending.is_empty() => println!("No pending migrations."),
+        Ok(pending) => {
+            for (version, description) in pending {
+                println!("{}: {}", version, description);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// `--purge-expired`: one janitor pass, for tests and cron jobs.
+async fn purge_expired_once(state: &AppState) {
+    match janitor::purge_expired(&state.db_action(), state.config.janitor_batch_size).await {
+        Ok(counts) => println!("Purged {} expired sessions and {} expired invites", counts.sessions, counts.invites),
+        Err(e) => {
+            eprintln!("Error purging expired rows: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Resolves on Ctrl+C or SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("failed to listen for Ctrl+C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
 #[tokio::main]
 async fn main() {
-    let state = AppState::new();
+    let cli = Cli::parse();
+    let config = match Config::load(&cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if cli.pending_migrations {
+        print_pending_migrations(&config).await;
+        return;
+    }
+
+    let bind = config.bind;
+    let static_dir = config.static_dir.clone();
+    let shutdown_timeout = config.shutdown_timeout;
+    let state = AppState::new(config).await;
+    if cli.purge_expired {
+        purge_expired_once(&state).await;
+        return;
+    }
+    let janitor = janitor::spawn(state.db_action(), state.config.janitor_interval, state.config.janitor_batch_size);
+    let shutdown_state = state.clone();
 
     let app = Router::new()
         .route("/", axum::routing::get(index))
         .route("/chat/:id", axum::routing::get(chat))
+        .route("/api/search", axum::routing::get(search))
+        .route("/api/chats/:id", axum::routing::patch(rename_chat).delete(delete_chat))
+        .route("/api/chats/:id/members", axum::routing::get(chat_members))
+        .route("/api/chats/:id/read", axum::routing::post(mark_read))
+        .route("/api/chats/:id/online", axum::routing::get(chat_online))
+        .route("/api/chats/:id/leave", axum::routing::post(leave_chat))
+        .route("/api/chats/:id/members/:user_id", axum::routing::delete(remove_member))
+        .route("/api/chats/:id/members/:user_id/role", axum::routing::put(set_member_role))
+        .route("/api/chats/:id/transfer", axum::routing::post(transfer_ownership))
+        .route("/api/chats/:id/invites", axum::routing::get(chat_invites))
+        .route("/api/chats/:id/invites/:code", axum::routing::delete(revoke_invite))
+        .route("/api/chats/:id/messages", axum::routing::get(chat_messages))
+        .route("/api/chats/:id/messages/:message_id/edits", axum::routing::get(message_edits))
         .route("/chatsocket/:id", axum::routing::get(chatsocket_handler))
         .route("/newchat", axum::routing::post(newchat))
-        .route("/invite/:code", axum::routing::get(invite))
+        .route("/invite/:code", axum::routing::get(invite).post(redeem_invite))
         .route("/create_invite/:chat_id", axum::routing::post(create_invite))
         .route("/status", axum::routing::get(status))
         .route("/auth", axum::routing::get(auth_get).post(auth_post))
+        .route("/register", axum::routing::get(register_get).post(register_post))
         .route("/logout", axum::routing::post(logout))
-        .nest_service("/static", ServeDir::new("static"))
-        .with_state(state);
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:1578").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+        .route("/sessions", axum::routing::get(sessions))
+        .route("/api/sessions/:id", axum::routing::delete(revoke_session))
+        .route("/api/sessions/revoke_others", axum::routing::post(revoke_other_sessions))
+        .nest_service("/static", ServeDir::new(static_dir))
+        .with_state(state.clone());
+    let listener = tokio::net::TcpListener::bind(bind).await.unwrap();
+    println!("Listening on {}", bind);
+    // Peer addresses are needed for per-IP rate limits, see `auth::ClientIp`
+    axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>())
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            println!("Shutting down, closing {} sockets...", shutdown_state.get_connected_clients());
+            shutdown_state.begin_shutdown();
+        })
+        .await
+        .unwrap();
+
+    janitor.abort();
+    // HTTP requests are done; socket tasks may still be finishing a message
+    let remaining = state.drain_connections(shutdown_timeout).await;
+    if remaining > 0 {
+        eprintln!("Gave up waiting for {} WebSocket connections", remaining);
+    }
+    match state.db_action().checkpoint().await {
+        Ok(_) => println!("Database checkpointed, bye."),
+        Err(e) => eprintln!("Error checkpointing database: {}", e),
+    }
 }
\ No newline at end of file
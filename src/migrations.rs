@@ -0,0 +1,464 @@
+//! Ordered schema migrations, tracked with `PRAGMA user_version`.
+//!
+//! `MIGRATIONS[i]` brings the schema from version `i` to `i + 1`. Each one runs
+//! in its own transaction together with the version bump, so a failed migration
+//! leaves the database at the previous version. Append new migrations to the
+//! end; never edit or reorder one that has shipped.
+//!
+//! Databases created before migrations existed are at version 0 and already
+//! have some of the tables, so the early migrations only add what is missing.
+
+use std::fmt;
+use crate::database::transaction;
+
+pub struct Migration {
+    pub description: &'static str,
+    apply: fn(&sqlite::Connection) -> Result<(), sqlite::Error>,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration { description: "initial schema", apply: initial_schema },
+    Migration { description: "message edits and deletion", apply: message_edits },
+    Migration { description: "case-insensitive unique usernames", apply: unique_usernames },
+    Migration { description: "chat member roles", apply: member_roles },
+    Migration { description: "invite usage limits and revocation", apply: invite_limits },
+    Migration { description: "read markers", apply: read_markers },
+    Migration { description: "full-text message search", apply: message_search },
+    Migration { description: "session CSRF tokens", apply: session_csrf_tokens },
+    Migration { description: "session devices and activity", apply: session_devices },
+    Migration { description: "expiry indexes", apply: expiry_indexes },
+];
+
+/// The schema version this binary writes.
+pub fn latest_version() -> i64 {
+    MIGRATIONS.len() as i64
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Sqlite(sqlite::Error),
+    /// The database was migrated by a newer build than this one.
+    TooNew { found: i64, supported: i64 },
+}
+
+impl From<sqlite::Error> for MigrationError {
+    fn from(e: sqlite::Error) -> Self {
+        MigrationError::Sqlite(e)
+    }
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Sqlite(e) => write!(f, "{}", e),
+            MigrationError::TooNew { found, supported } => write!(
+                f,
+                "database schema version {} is newer than the {} this build supports",
+                found, supported
+            ),
+        }
+    }
+}
+
+pub fn schema_version(conn: &sqlite::Connection) -> Result<i64, sqlite::Error> {
+    let mut stmt = conn.prepare("PRAGMA user_version;")?;
+    stmt.next()?;
+    stmt.read(0)
+}
+
+/// Migrations not yet applied, as (version they migrate to, migration).
+pub fn pending(conn: &sqlite::Connection) -> Result<Vec<(i64, &'static Migration)>, MigrationError> {
+    let current = schema_version(conn)?;
+    if current > latest_version() {
+        return Err(MigrationError::TooNew { found: current, supported: latest_version() });
+    }
+    Ok(MIGRATIONS
+        .iter()
+        .enumerate()
+        .skip(current as usize)
+        .map(|(i, migration)| (i as i64 + 1, migration))
+        .collect())
+}
+
+/// Applies every pending migration in order.
+pub fn migrate(conn: &sqlite::Connection) -> Result<(), MigrationError> {
+    for (version, migration) in pending(conn)? {
+        println!("Applying migration {}: {}", version, migration.description);
+        transaction(conn, || {
+            (migration.apply)(conn)?;
+            conn.execute(format!("PRAGMA user_version = {};", version))
+        })?;
+    }
+    Ok(())
+}
+
+fn has_column(conn: &sqlite::Connection, table: &str, column: &str) -> Result<bool, sqlite::Error> {
+    let mut stmt = conn.prepare("SELECT 1 FROM pragma_table_info(?) WHERE name = ?;")?;
+    stmt.bind((1, table))?;
+    stmt.bind((2, column))?;
+    Ok(matches!(stmt.next()?, sqlite::State::Row))
+}
+
+/// Adds a column unless a pre-migration build already did. Returns whether it was added.
+fn add_column(conn: &sqlite::Connection, table: &str, column: &str, definition: &str) -> Result<bool, sqlite::Error> {
+    if has_column(conn, table, column)? {
+        return Ok(false);
+    }
+    conn.execute(format!("ALTER TABLE {} ADD COLUMN {} {};", table, column, definition))?;
+    Ok(true)
+}
+
+fn initial_schema(conn: &sqlite::Connection) -> Result<(), sqlite::Error> {
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS Messages (
+            messageID INTEGER PRIMARY KEY,
+            message_text TEXT NOT NULL,
+            username TEXT NOT NULL,
+            chatID INTEGER NOT NULL,
+            timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY(chatID) REFERENCES Chats(chatID) ON DELETE CASCADE
+        );
+        CREATE TABLE IF NOT EXISTS Users (
+            userID INTEGER PRIMARY KEY,
+            username TEXT NOT NULL,
+            password_hash TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS Sessions (
+            sessionID INTEGER PRIMARY KEY,
+            userID INTEGER NOT NULL,
+            session_token TEXT NOT NULL,
+            expires_at DATETIME NOT NULL,
+            FOREIGN KEY(userID) REFERENCES Users(userID)
+        );
+        CREATE TABLE IF NOT EXISTS Chats (
+            chatID INTEGER PRIMARY KEY,
+            chat_name TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS ChatMembers (
+            chatID INTEGER NOT NULL,
+            userID INTEGER NOT NULL,
+            PRIMARY KEY (chatID, userID),
+            FOREIGN KEY(chatID) REFERENCES Chats(chatID) ON DELETE CASCADE,
+            FOREIGN KEY(userID) REFERENCES Users(userID) ON DELETE CASCADE
+        ) WITHOUT ROWID;
+        CREATE TABLE IF NOT EXISTS InviteCodes (
+            code TEXT PRIMARY KEY,
+            chatID INTEGER NOT NULL,
+            expires_at DATETIME NOT NULL,
+            FOREIGN KEY(chatID) REFERENCES Chats(chatID) ON DELETE CASCADE
+        );
+        ",
+    )
+}
+
+fn message_edits(conn: &sqlite::Connection) -> Result<(), sqlite::Error> {
+    add_column(conn, "Messages", "edited_at", "DATETIME")?;
+    add_column(conn, "Messages", "deleted_at", "DATETIME")?;
+    conn.execute(
+        "
+        CREATE INDEX IF NOT EXISTS idx_messages_chat ON Messages(chatID, messageID);
+        CREATE TABLE IF NOT EXISTS MessageEdits (
+            editID INTEGER PRIMARY KEY,
+            messageID INTEGER NOT NULL,
+            previous_text TEXT NOT NULL,
+            edited_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY(messageID) REFERENCES Messages(messageID) ON DELETE CASCADE
+        );
+        ",
+    )
+}
+
+fn unique_usernames(conn: &sqlite::Connection) -> Result<(), sqlite::Error> {
+    // Concurrent sign-ups could create accounts differing only in case, or not at
+    // all. The oldest keeps the name; the others become `<name>-2`, `<name>-3`, ...
+    // Messages only record the author's name, so they follow the rename when no
+    // other account has exactly the same name.
+    let duplicates: Vec<(i64, String, bool)> = {
+        let mut stmt = conn.prepare(
+            "SELECT userID, username,
+                    (SELECT COUNT(*) FROM Users AS same WHERE same.username = u.username) = 1
+                FROM Users AS u
+                WHERE EXISTS (
+                    SELECT 1 FROM Users AS older
+                    WHERE older.username = u.username COLLATE NOCASE AND older.userID < u.userID
+                )
+                ORDER BY userID;",
+        )?;
+        let mut duplicates = Vec::new();
+        while let sqlite::State::Row = stmt.next()? {
+            duplicates.push((stmt.read(0)?, stmt.read(1)?, stmt.read::<i64, _>(2)? == 1));
+        }
+        duplicates
+    };
+    for (user_id, username, unambiguous) in duplicates {
+        let new_name = free_username(conn, &username)?;
+        let mut stmt = conn.prepare("UPDATE Users SET username = ? WHERE userID = ?;")?;
+        stmt.bind((1, new_name.as_str()))?;
+        stmt.bind((2, user_id))?;
+        stmt.next()?;
+        if unambiguous {
+            let mut stmt = conn.prepare("UPDATE Messages SET username = ? WHERE username = ?;")?;
+            stmt.bind((1, new_name.as_str()))?;
+            stmt.bind((2, username.as_str()))?;
+            stmt.next()?;
+        }
+    }
+    conn.execute("CREATE UNIQUE INDEX IF NOT EXISTS idx_users_username ON Users(username COLLATE NOCASE);")
+}
+
+/// The first of `<username>-2`, `<username>-3`, ... nobody has, shortened to
+/// stay within the 32 characters `validate_username` allows.
+fn free_username(conn: &sqlite::Connection, username: &str) -> Result<String, sqlite::Error> {
+    let mut stmt = conn.prepare("SELECT 1 FROM Users WHERE username = ? COLLATE NOCASE;")?;
+    for n in 2.. {
+        let suffix = format!("-{}", n);
+        let base: String = username.chars().take(32usize.saturating_sub(suffix.len())).collect();
+        let candidate = format!("{}{}", base, suffix);
+        stmt.reset()?;
+        stmt.bind((1, candidate.as_str()))?;
+        if let sqlite::State::Done = stmt.next()? {
+            return Ok(candidate);
+        }
+    }
+    unreachable!()
+}
+
+fn member_roles(conn: &sqlite::Connection) -> Result<(), sqlite::Error> {
+    add_column(
+        conn,
+        "ChatMembers",
+        "role",
+        "TEXT NOT NULL DEFAULT 'member' CHECK (role IN ('owner', 'admin', 'member'))",
+    )?;
+    // Chats from before roles have no owner; the earliest registered member gets it
+    conn.execute(
+        "UPDATE ChatMembers SET role = 'owner'
+            WHERE (chatID, userID) IN (
+                SELECT chatID, MIN(userID) FROM ChatMembers
+                GROUP BY chatID
+                HAVING SUM(role = 'owner') = 0
+            );",
+    )
+}
+
+fn invite_limits(conn: &sqlite::Connection) -> Result<(), sqlite::Error> {
+    if has_column(conn, "InviteCodes", "max_uses")? {
+        return Ok(());
+    }
+    // created_at needs a non-constant default, which ALTER TABLE cannot add
+    conn.execute(
+        "
+        CREATE TABLE InviteCodes_new (
+            code TEXT PRIMARY KEY,
+            chatID INTEGER NOT NULL,
+            creatorID INTEGER,
+            max_uses INTEGER,
+            use_count INTEGER NOT NULL DEFAULT 0,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            expires_at DATETIME NOT NULL,
+            revoked_at DATETIME,
+            FOREIGN KEY(chatID) REFERENCES Chats(chatID) ON DELETE CASCADE,
+            FOREIGN KEY(creatorID) REFERENCES Users(userID) ON DELETE SET NULL
+        );
+        INSERT INTO InviteCodes_new (code, chatID, expires_at)
+            SELECT code, chatID, expires_at FROM InviteCodes;
+        DROP TABLE InviteCodes;
+        ALTER TABLE InviteCodes_new RENAME TO InviteCodes;
+        ",
+    )
+}
+
+fn read_markers(conn: &sqlite::Connection) -> Result<(), sqlite::Error> {
+    if add_column(conn, "ChatMembers", "last_read_message_id", "INTEGER NOT NULL DEFAULT 0")? {
+        // Don't greet existing members with their whole history as unread
+        conn.execute(
+            "UPDATE ChatMembers SET last_read_message_id =
+                (SELECT COALESCE(MAX(messageID), 0) FROM Messages WHERE Messages.chatID = ChatMembers.chatID);",
+        )?;
+    }
+    Ok(())
+}
+
+fn message_search(conn: &sqlite::Connection) -> Result<(), sqlite::Error> {
+    let fts_exists = {
+        let mut stmt = conn.prepare("SELECT 1 FROM sqlite_master WHERE name = 'MessagesFTS';")?;
+        matches!(stmt.next()?, sqlite::State::Row)
+    };
+    if fts_exists {
+        return Ok(());
+    }
+    // Full-text index over message text, kept in sync by the triggers below.
+    // Edits and tombstones go through the update trigger.
+    conn.execute(
+        "
+        CREATE VIRTUAL TABLE MessagesFTS USING fts5(
+            message_text,
+            content='Messages',
+            content_rowid='messageID',
+            tokenize='unicode61 remove_diacritics 2'
+        );
+        CREATE TRIGGER messages_fts_insert AFTER INSERT ON Messages BEGIN
+            INSERT INTO MessagesFTS(rowid, message_text) VALUES (new.messageID, new.message_text);
+        END;
+        CREATE TRIGGER messages_fts_delete AFTER DELETE ON Messages BEGIN
+            INSERT INTO MessagesFTS(MessagesFTS, rowid, message_text) VALUES ('delete', old.messageID, old.message_text);
+        END;
+        CREATE TRIGGER messages_fts_update AFTER UPDATE OF message_text ON Messages BEGIN
+            INSERT INTO MessagesFTS(MessagesFTS, rowid, message_text) VALUES ('delete', old.messageID, old.message_text);
+            INSERT INTO MessagesFTS(rowid, message_text) VALUES (new.messageID, new.message_text);
+        END;
+        INSERT INTO MessagesFTS(MessagesFTS) VALUES ('rebuild');
+        ",
+    )
+}
+
+fn session_csrf_tokens(conn: &sqlite::Connection) -> Result<(), sqlite::Error> {
+    add_column(conn, "Sessions", "csrf_token", "TEXT NOT NULL DEFAULT ''")?;
+    // Sessions that are already open get a token too, rather than being logged out
+    conn.execute("UPDATE Sessions SET csrf_token = lower(hex(randomblob(16))) WHERE csrf_token = '';")
+}
+
+fn session_devices(conn: &sqlite::Connection) -> Result<(), sqlite::Error> {
+    // Unknown for sessions opened before this, and left NULL
+    add_column(conn, "Sessions", "created_at", "DATETIME")?;
+    add_column(conn, "Sessions", "last_seen_at", "DATETIME")?;
+    add_column(conn, "Sessions", "user_agent", "TEXT")?;
+    add_column(conn, "Sessions", "ip", "TEXT")?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_sessions_user ON Sessions(userID);")
+}
+
+// For the janitor, which looks for expired rows in batches
+fn expiry_indexes(conn: &sqlite::Connection) -> Result<(), sqlite::Error> {
+    conn.execute(
+        "
+        CREATE INDEX IF NOT EXISTS idx_sessions_expires_at ON Sessions(expires_at);
+        CREATE INDEX IF NOT EXISTS idx_invite_codes_expires_at ON InviteCodes(expires_at);
+        ",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A database as the server created it before migrations existed, at version 0.
+    fn baseline() -> sqlite::Connection {
+        let conn = sqlite::open(":memory:").unwrap();
+        initial_schema(&conn).unwrap();
+        conn.execute(
+            "
+            INSERT INTO Users (userID, username, password_hash) VALUES
+                (1, 'alice', 'hash1'), (2, 'Alice', 'hash2'), (3, 'bob', 'hash3'), (4, 'alice', 'hash4');
+            INSERT INTO Sessions (userID, session_token, expires_at) VALUES
+                (1, 'token1', '2030-01-01 00:00:00'), (4, 'token4', '2030-01-01 00:00:00');
+            INSERT INTO Chats (chatID, chat_name) VALUES (1, 'general'), (2, 'empty');
+            INSERT INTO ChatMembers (chatID, userID) VALUES (1, 3), (1, 2), (2, 4);
+            INSERT INTO Messages (message_text, username, chatID) VALUES
+                ('hi', 'bob', 1), ('hello', 'Alice', 1), ('bye', 'bob', 1), ('hey', 'alice', 1);
+            INSERT INTO InviteCodes (code, chatID, expires_at) VALUES ('abc', 1, '2030-01-01 00:00:00');
+            ",
+        )
+        .unwrap();
+        conn
+    }
+
+    /// The first column of every row `sql` returns.
+    fn column<T: sqlite::ReadableWithIndex>(conn: &sqlite::Connection, sql: &str) -> Vec<T> {
+        let mut stmt = conn.prepare(sql).unwrap();
+        let mut values = Vec::new();
+        while let sqlite::State::Row = stmt.next().unwrap() {
+            values.push(stmt.read(0).unwrap());
+        }
+        values
+    }
+
+    #[test]
+    fn migrates_a_baseline_database() {
+        let conn = baseline();
+        migrate(&conn).unwrap();
+
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+        assert!(pending(&conn).unwrap().is_empty());
+
+        // The oldest account keeps a duplicated name
+        assert_eq!(
+            column::<String>(&conn, "SELECT username FROM Users ORDER BY userID;"),
+            ["alice", "Alice-2", "bob", "alice-3"]
+        );
+        assert_eq!(
+            column::<String>(&conn, "SELECT password_hash FROM Users ORDER BY userID;"),
+            ["hash1", "hash2", "hash3", "hash4"]
+        );
+
+        // Sessions survive, each with its own CSRF token
+        assert_eq!(
+            column::<String>(&conn, "SELECT userID || ':' || session_token FROM Sessions ORDER BY sessionID;"),
+            ["1:token1", "4:token4"]
+        );
+        let csrf_tokens = column::<String>(&conn, "SELECT csrf_token FROM Sessions;");
+        assert!(csrf_tokens.iter().all(|token| token.len() == 32));
+        assert_ne!(csrf_tokens[0], csrf_tokens[1]);
+
+        // InviteCodes was rebuilt around the new columns
+        assert_eq!(
+            column::<String>(
+                &conn,
+                "SELECT code || ':' || chatID || ':' || expires_at || ':' || use_count || ':'
+                    || (max_uses IS NULL) || (creatorID IS NULL) || (revoked_at IS NULL) || (created_at IS NOT NULL)
+                    FROM InviteCodes;"
+            ),
+            ["abc:1:2030-01-01 00:00:00:0:1111"]
+        );
+
+        // The earliest registered member owns each chat, and history starts out read
+        assert_eq!(
+            column::<String>(
+                &conn,
+                "SELECT chatID || ':' || userID || ':' || role || ':' || last_read_message_id
+                    FROM ChatMembers ORDER BY chatID, userID;"
+            ),
+            ["1:2:owner:4", "1:3:member:4", "2:4:owner:0"]
+        );
+
+        // Messages follow a rename unless another account had exactly the same name
+        assert_eq!(
+            column::<String>(&conn, "SELECT username FROM Messages ORDER BY messageID;"),
+            ["bob", "Alice-2", "bob", "alice"]
+        );
+
+        // Existing messages are searchable
+        assert_eq!(column::<i64>(&conn, "SELECT rowid FROM MessagesFTS WHERE MessagesFTS MATCH 'hello';"), [2]);
+    }
+
+    #[test]
+    fn unique_usernames_are_enforced_after_migrating() {
+        let conn = baseline();
+        migrate(&conn).unwrap();
+        let err = conn.execute("INSERT INTO Users (username, password_hash) VALUES ('BOB', 'x');").unwrap_err();
+        assert!(crate::database::is_constraint_violation(&err));
+    }
+
+    #[test]
+    fn migrates_an_empty_database() {
+        let conn = sqlite::open(":memory:").unwrap();
+        migrate(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+        // Running again finds nothing to do
+        migrate(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn refuses_a_newer_schema() {
+        let conn = sqlite::open(":memory:").unwrap();
+        conn.execute(format!("PRAGMA user_version = {};", latest_version() + 1)).unwrap();
+        match pending(&conn) {
+            Err(MigrationError::TooNew { found, supported }) => {
+                assert_eq!(found, latest_version() + 1);
+                assert_eq!(supported, latest_version());
+            }
+            _ => panic!("expected TooNew"),
+        }
+    }
+}
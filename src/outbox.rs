@@ -0,0 +1,269 @@
+//! Bounded outbound queue of a WebSocket connection.
+//!
+//! Everything sent to a socket (broadcasts, replies, close frames) goes through
+//! its `Outbox`, which the connection's writer task drains. A client that stops
+//! reading fills its outbox; what happens next is the `OverflowPolicy`.
+
+use axum::extract::ws::{CloseFrame, Message};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use crate::protocol::CLOSE_TOO_SLOW;
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued message to make room
+    DropOldest,
+    /// Close the connection with `CLOSE_TOO_SLOW`
+    Disconnect,
+}
+
+/// Server-wide overflow counters, reported by `/status`.
+#[derive(Default)]
+pub struct OutboxStats {
+    pub dropped_messages: AtomicU64,
+    pub slow_disconnects: AtomicU64,
+}
+
+pub struct Outbox {
+    queue: Mutex<Queue>,
+    // Wakes the writer when something was queued
+    ready: Notify,
+    // Wakes the connection when it was disconnected for being too slow
+    overflowed: Notify,
+    capacity: usize,
+    policy: OverflowPolicy,
+    stats: Arc<OutboxStats>,
+}
+
+struct Queue {
+    messages: VecDeque<Message>,
+    // No more messages are accepted: a close frame was queued or the writer is gone
+    closed: bool,
+    // Messages this connection lost to `DropOldest`
+    dropped: u64,
+    // How many of the front messages were queued by `prepend`; they don't count
+    // toward the capacity and are never dropped
+    prepended: usize,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize, policy: OverflowPolicy, stats: Arc<OutboxStats>) -> Self {
+        Outbox {
+            queue: Mutex::new(Queue { messages: VecDeque::new(), closed: false, dropped: 0, prepended: 0 }),
+            ready: Notify::new(),
+            overflowed: Notify::new(),
+            capacity,
+            policy,
+            stats,
+        }
+    }
+
+    /// Queues a message for the writer. A close frame is always accepted and is
+    /// the last thing queued. Returns false if the message was not queued.
+    pub fn push(&self, message: Message) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.closed {
+            return false;
+        }
+        if matches!(message, Message::Close(_)) {
+            queue.closed = true;
+        } else if queue.messages.len() - queue.prepended >= self.capacity {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    let oldest = queue.prepended;
+                    queue.messages.remove(oldest);
+                    queue.dropped += 1;
+                    self.stats.dropped_messages.fetch_add(1, Ordering::Relaxed);
+                }
+                OverflowPolicy::Disconnect => {
+                    // Nothing queued matters any more, just the close frame
+                    queue.messages.clear();
+                    queue.prepended = 0;
+                    queue.messages.push_back(Message::Close(Some(CloseFrame {
+                        code: CLOSE_TOO_SLOW,
+                        reason: "Too slow".into(),
+                    })));
+                    queue.closed = true;
+                    self.stats.slow_disconnects.fetch_add(1, Ordering::Relaxed);
+                    self.ready.notify_one();
+                    self.overflowed.notify_one();
+                    return false;
+                }
+            }
+        }
+        queue.messages.push_back(message);
+        self.ready.notify_one();
+        true
+    }
+
+    /// Queues `messages` ahead of everything already queued, regardless of
+    /// capacity. Used to send the connection's greeting and replayed history
+    /// before the live broadcasts that arrived while they were being loaded.
+    pub fn prepend(&self, messages: Vec<Message>) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.closed {
+            return;
+        }
+        queue.prepended += messages.len();
+        for message in messages.into_iter().rev() {
+            queue.messages.push_front(message);
+        }
+        self.ready.notify_one();
+    }
+
+    /// Next message to write, or None once the outbox is closed and empty.
+    pub async fn pop(&self) -> Option<Message> {
+        loop {
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if let Some(message) = queue.messages.pop_front() {
+                    queue.prepended = queue.prepended.saturating_sub(1);
+                    return Some(message);
+                }
+                if queue.closed {
+                    return None;
+                }
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    /// Stops accepting messages, for when the writer has gone away.
+    pub fn close(&self) {
+        self.queue.lock().unwrap().closed = true;
+        self.ready.notify_one();
+    }
+
+    /// Resolves when the connection is disconnected under `OverflowPolicy::Disconnect`.
+    pub async fn overflowed(&self) {
+        self.overflowed.notified().await;
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.queue.lock().unwrap().dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outbox(capacity: usize, policy: OverflowPolicy) -> (Outbox, Arc<OutboxStats>) {
+        let stats = Arc::new(OutboxStats::default());
+        (Outbox::new(capacity, policy, stats.clone()), stats)
+    }
+
+    fn text(text: &str) -> Message {
+        Message::Text(text.to_string())
+    }
+
+    /// What is queued, front first, with close frames as `close <code>`.
+    fn queued(outbox: &Outbox) -> Vec<String> {
+        let queue = outbox.queue.lock().unwrap();
+        queue
+            .messages
+            .iter()
+            .map(|message| match message {
+                Message::Text(text) => text.clone(),
+                Message::Close(Some(frame)) => format!("close {}", frame.code),
+                _ => "other".to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn prepended_messages_go_first_and_dont_count_toward_capacity() {
+        let (outbox, _) = outbox(2, OverflowPolicy::Disconnect);
+        assert!(outbox.push(text("live 1")));
+        outbox.prepend(vec![text("hello"), text("presence"), text("resumed")]);
+        assert!(outbox.push(text("live 2")));
+        assert_eq!(queued(&outbox), ["hello", "presence", "resumed", "live 1", "live 2"]);
+        assert_eq!(outbox.queue.lock().unwrap().prepended, 3);
+    }
+
+    #[tokio::test]
+    async fn popping_a_prepended_message_leaves_the_capacity_alone() {
+        let (outbox, stats) = outbox(1, OverflowPolicy::DropOldest);
+        outbox.prepend(vec![text("hello"), text("presence")]);
+        assert!(outbox.push(text("live 1")));
+        assert_eq!(outbox.pop().await.unwrap(), text("hello"));
+        assert_eq!(outbox.queue.lock().unwrap().prepended, 1);
+        // Still one live message queued, so the next one replaces it
+        assert!(outbox.push(text("live 2")));
+        assert_eq!(queued(&outbox), ["presence", "live 2"]);
+        assert_eq!(stats.dropped_messages.load(Ordering::Relaxed), 1);
+        // Once the prepended messages are gone the live ones are counted as before
+        assert_eq!(outbox.pop().await.unwrap(), text("presence"));
+        assert_eq!(outbox.queue.lock().unwrap().prepended, 0);
+        assert!(outbox.push(text("live 3")));
+        assert_eq!(queued(&outbox), ["live 3"]);
+    }
+
+    #[test]
+    fn drop_oldest_drops_the_oldest_live_message_only() {
+        let (outbox, stats) = outbox(2, OverflowPolicy::DropOldest);
+        outbox.prepend(vec![text("hello"), text("resumed")]);
+        for n in 1..=5 {
+            assert!(outbox.push(text(&format!("live {}", n))));
+        }
+        assert_eq!(queued(&outbox), ["hello", "resumed", "live 4", "live 5"]);
+        assert_eq!(outbox.dropped(), 3);
+        assert_eq!(stats.dropped_messages.load(Ordering::Relaxed), 3);
+        assert_eq!(stats.slow_disconnects.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn disconnect_replaces_the_queue_with_a_close_frame() {
+        let (outbox, stats) = outbox(2, OverflowPolicy::Disconnect);
+        outbox.prepend(vec![text("hello")]);
+        assert!(outbox.push(text("live 1")));
+        assert!(outbox.push(text("live 2")));
+        assert!(!outbox.push(text("live 3")));
+        assert_eq!(queued(&outbox), [format!("close {}", CLOSE_TOO_SLOW)]);
+        assert_eq!(outbox.queue.lock().unwrap().prepended, 0);
+        // Closed now, nothing else is queued
+        assert!(!outbox.push(text("live 4")));
+        assert!(!outbox.push(Message::Close(None)));
+        outbox.prepend(vec![text("late")]);
+        assert_eq!(queued(&outbox).len(), 1);
+        assert_eq!(stats.slow_disconnects.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.dropped_messages.load(Ordering::Relaxed), 0);
+        assert_eq!(outbox.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn disconnect_wakes_the_connection() {
+        let (outbox, _) = outbox(0, OverflowPolicy::Disconnect);
+        let outbox = Arc::new(outbox);
+        let waiting = tokio::spawn({
+            let outbox = outbox.clone();
+            async move { outbox.overflowed().await }
+        });
+        assert!(!outbox.push(text("live 1")));
+        tokio::time::timeout(std::time::Duration::from_secs(1), waiting).await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn a_close_frame_is_accepted_when_full_and_is_the_last_message() {
+        let (outbox, stats) = outbox(1, OverflowPolicy::Disconnect);
+        assert!(outbox.push(text("live 1")));
+        assert!(outbox.push(Message::Close(Some(CloseFrame { code: 4001, reason: "Removed".into() }))));
+        assert!(!outbox.push(text("live 2")));
+        assert_eq!(queued(&outbox), ["live 1", "close 4001"]);
+        assert_eq!(stats.slow_disconnects.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn pop_drains_what_is_left_after_closing() {
+        let (outbox, _) = outbox(2, OverflowPolicy::DropOldest);
+        assert!(outbox.push(text("live 1")));
+        outbox.close();
+        assert!(!outbox.push(text("live 2")));
+        assert_eq!(outbox.pop().await, Some(text("live 1")));
+        assert_eq!(outbox.pop().await, None);
+    }
+}
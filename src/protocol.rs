@@ -0,0 +1,211 @@
+//! Wire format for `/chatsocket/:id`.
+//!
+//! The client picks a protocol version with the `v` query parameter when it
+//! connects (`/chatsocket/7?v=1`). The server answers with a `hello` frame
+//! carrying the version it will actually speak, which is the highest version
+//! it supports that is not newer than the one requested.
+//!
+//! Version 0 is the legacy plain-text mode: the client sends raw message text
+//! and receives `"<author>: <text>"` lines. It only exists to keep old clients
+//! working during the migration, so a client has to ask for it with `v=0` and
+//! the server has to allow it with `allow_legacy_protocol`. A missing `v` is
+//! refused.
+//!
+//! Version 1 frames are JSON objects tagged by `type`.
+//!
+//! Client → server:
+//! - `message` `{ text }` — post a message to the chat
+//! - `edit_message` `{ id, text }` — replace the text of one of your messages
+//! - `delete_message` `{ id }` — delete one of your messages
+//! - `typing_started` — the user is composing a message; repeat every few
+//!   seconds while they keep typing, otherwise it expires on its own
+//! - `typing_stopped` — the user stopped typing without sending
+//! - `mark_read` `{ message_id }` — the user has read everything up to `message_id`
+//! - `ping` — keepalive for clients whose proxies strip WebSocket ping frames;
+//!   answered with `pong`
+//!
+//! Server → client:
+//! - `hello` `{ version, chat_id, server_time }` — first frame after connecting;
+//!   `server_time` is the moment from which live events are delivered
+//! - `presence` `{ chat_id, online: [{ user_id, username }] }` — who is online,
+//!   sent once right after `hello`
+//! - `resumed` `{ chat_id, last_message_id, complete }` — end of the replay
+//!   requested with `last_seen`, see below
+//! - `presence_joined` `{ chat_id, user_id, username }` — a user opened their first socket in the chat
+//! - `presence_left` `{ chat_id, user_id, username }` — a user closed their last socket in the chat
+//! - `message` `{ id, chat_id, author, text, sent_at }` — a new message
+//! - `message_edited` `{ id, chat_id, text, edited_at }` — a message was edited
+//! - `message_deleted` `{ id, chat_id }` — a message was deleted
+//! - `typing_started` / `typing_stopped` `{ chat_id, user_id, username }` — another
+//!   member started or stopped typing; never persisted
+//! - `read_receipt` `{ chat_id, user_id, username, message_id }` — a member's read
+//!   marker moved forward
+//! - `pong` — reply to `ping`
+//! - `error` `{ code, message }` — the last client frame was rejected
+//!
+//! A client reconnecting after a drop passes the newest message ID it has as
+//! `last_seen` (`/chatsocket/7?v=1&last_seen=120`), and as `since` the
+//! `server_time` of the last connection that resumed completely, or the time
+//! the page was rendered. After `presence` the server first sends
+//! `message_edited` or `message_deleted` for messages up to `last_seen` that
+//! changed at or after `since`, then replays every message of the chat newer
+//! than `last_seen`, each as a `message` followed by `message_edited` or
+//! `message_deleted` if it has since changed, then sends `resumed`. Live events follow. Everything up to `last_message_id`
+//! has been sent by then, so a later `message` with an ID at or below it is a
+//! duplicate. If more than `resume_max_messages` were missed or changed nothing is
+//! replayed and `complete` is false: the client should reload the history
+//! instead.
+//!
+//! The server also sends WebSocket ping frames every `heartbeat_interval_secs`.
+//! A client that sends nothing at all (no pong, no frame of any kind) for
+//! `heartbeat_missed_limit` pings in a row is disconnected.
+//!
+//! When the user stops being a member of the chat, stops reading fast enough,
+//! stops answering heartbeats, ignores rate limits, their session is revoked or
+//! the server shuts down, the server closes the socket with one of the
+//! `CLOSE_*` codes below and a human-readable reason.
+
+use serde::{Deserialize, Serialize};
+
+pub const LEGACY_VERSION: u32 = 0;
+pub const CURRENT_VERSION: u32 = 1;
+
+/// The user left or was removed from the chat.
+pub const CLOSE_MEMBERSHIP_ENDED: u16 = 4001;
+/// The chat was deleted.
+pub const CLOSE_CHAT_DELETED: u16 = 4002;
+/// The client fell too far behind reading what the server sent it.
+pub const CLOSE_TOO_SLOW: u16 = 4003;
+/// The client missed too many heartbeats.
+pub const CLOSE_HEARTBEAT_TIMEOUT: u16 = 4004;
+/// The client kept sending after being told it was rate limited.
+pub const CLOSE_RATE_LIMITED: u16 = 4005;
+/// The login session the socket was opened with was revoked or logged out.
+pub const CLOSE_SESSION_REVOKED: u16 = 4006;
+/// The server is shutting down (the standard "Service Restart" code).
+pub const CLOSE_SERVER_RESTARTING: u16 = 1012;
+
+/// Picks the version to speak for a client that asked for `requested`, or
+/// says why the request is refused.
+pub fn negotiate_version(requested: Option<u32>, allow_legacy: bool) -> Result<u32, &'static str> {
+    match requested {
+        None => Err("Missing protocol version, connect with ?v=1"),
+        Some(LEGACY_VERSION) if !allow_legacy => Err("The legacy plain-text protocol is disabled, connect with ?v=1"),
+        Some(v) => Ok(v.min(CURRENT_VERSION)),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientEvent {
+    Message { text: String },
+    EditMessage { id: i64, text: String },
+    DeleteMessage { id: i64 },
+    TypingStarted,
+    TypingStopped,
+    MarkRead { message_id: i64 },
+    Ping,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    Hello {
+        version: u32,
+        chat_id: i64,
+        server_time: Option<String>,
+    },
+    Presence {
+        chat_id: i64,
+        online: Vec<OnlineUser>,
+    },
+    Resumed {
+        chat_id: i64,
+        last_message_id: i64,
+        complete: bool,
+    },
+    PresenceJoined {
+        chat_id: i64,
+        user_id: i64,
+        username: String,
+    },
+    PresenceLeft {
+        chat_id: i64,
+        user_id: i64,
+        username: String,
+    },
+    Message {
+        id: i64,
+        chat_id: i64,
+        author: String,
+        text: String,
+        sent_at: String,
+    },
+    MessageEdited {
+        id: i64,
+        chat_id: i64,
+        text: String,
+        edited_at: String,
+    },
+    MessageDeleted {
+        id: i64,
+        chat_id: i64,
+    },
+    TypingStarted {
+        chat_id: i64,
+        user_id: i64,
+        username: String,
+    },
+    TypingStopped {
+        chat_id: i64,
+        user_id: i64,
+        username: String,
+    },
+    ReadReceipt {
+        chat_id: i64,
+        user_id: i64,
+        username: String,
+        message_id: i64,
+    },
+    Pong,
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+#[derive(Serialize, Clone)]
+pub struct OnlineUser {
+    pub user_id: i64,
+    pub username: String,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,
+    NotFound,
+    Forbidden,
+    // The event was dropped, see `rate_limits` in the config
+    RateLimited,
+    Internal,
+}
+
+impl ServerEvent {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        ServerEvent::Error { code, message: message.into() }
+    }
+
+    /// Encodes the event for a socket speaking `version`. Returns `None` if the
+    /// event has no representation in that version (legacy clients only
+    /// understand chat messages).
+    pub fn encode(&self, version: u32) -> Option<String> {
+        if version == LEGACY_VERSION {
+            return match self {
+                ServerEvent::Message { author, text, .. } => Some(format!("{}: {}", author, text)),
+                _ => None,
+            };
+        }
+        serde_json::to_string(self).ok()
+    }
+}
@@ -0,0 +1,294 @@
+//! Token-bucket rate limiting of logins, invite redemption and socket events.
+//!
+//! Every `Action` has a `Rate`: a bucket holding up to `burst` tokens that
+//! refills at `per_minute` tokens a minute. Each attempt takes a token from the
+//! bucket of every key it is limited by (the user, the client IP, the username
+//! being logged into) and is refused if any of them is empty. Buckets live in
+//! memory, like the socket hub, and are forgotten once they have refilled.
+
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often buckets that have refilled are dropped.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    // HTTP routes
+    Login,
+    Register,
+    RedeemInvite,
+    // Socket events
+    Message,
+    EditMessage,
+    DeleteMessage,
+    Typing,
+    MarkRead,
+    Ping,
+}
+
+impl Action {
+    /// Rates used for the actions the config file doesn't mention.
+    pub fn default_rates() -> HashMap<Action, Rate> {
+        HashMap::from([
+            (Action::Login, Rate { burst: 10, per_minute: 10 }),
+            (Action::Register, Rate { burst: 5, per_minute: 2 }),
+            (Action::RedeemInvite, Rate { burst: 10, per_minute: 10 }),
+            (Action::Message, Rate { burst: 20, per_minute: 60 }),
+            (Action::EditMessage, Rate { burst: 10, per_minute: 30 }),
+            (Action::DeleteMessage, Rate { burst: 10, per_minute: 30 }),
+            // The page repeats typing_started every few seconds and marks each new message read
+            (Action::Typing, Rate { burst: 20, per_minute: 60 }),
+            (Action::MarkRead, Rate { burst: 30, per_minute: 120 }),
+            (Action::Ping, Rate { burst: 10, per_minute: 20 }),
+        ])
+    }
+
+    /// Socket events the page sends on its own rather than on the user's say.
+    /// Many tabs or a busy chat can legitimately exceed their rates, so they are
+    /// only limited per user and an over-limit one is dropped without complaint.
+    pub fn is_automatic(&self) -> bool {
+        matches!(self, Action::Typing | Action::MarkRead | Action::Ping)
+    }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rate {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+impl Rate {
+    fn per_second(&self) -> f64 {
+        self.per_minute as f64 / 60.0
+    }
+}
+
+/// Who an attempt is counted against.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum Key {
+    Ip(IpAddr),
+    User(i64),
+    // The account a login is for, so guessing one password from many addresses is
+    // limited too. Build it with `Key::username`
+    Username(String),
+}
+
+impl Key {
+    /// Usernames are looked up case-insensitively, so "alice" and "ALICE" have
+    /// to share a bucket.
+    pub fn username(username: &str) -> Key {
+        Key::Username(username.to_lowercase())
+    }
+}
+
+pub struct RateLimiter {
+    rates: HashMap<Action, Rate>,
+    buckets: Mutex<Buckets>,
+}
+
+struct Buckets {
+    by_key: HashMap<(Action, Key), Bucket>,
+    last_prune: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rates: HashMap<Action, Rate>) -> Self {
+        RateLimiter {
+            rates,
+            buckets: Mutex::new(Buckets { by_key: HashMap::new(), last_prune: Instant::now() }),
+        }
+    }
+
+    /// Takes a token for `action` from the bucket of each of `keys`. If any of
+    /// them is empty nothing is taken, and the error is how long until the
+    /// attempt would be allowed.
+    pub fn check(&self, action: Action, keys: &[Key]) -> Result<(), Duration> {
+        self.check_at(action, keys, Instant::now())
+    }
+
+    fn check_at(&self, action: Action, keys: &[Key], now: Instant) -> Result<(), Duration> {
+        let Some(rate) = self.rates.get(&action).copied() else {
+            return Ok(());
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        if now.duration_since(buckets.last_prune) >= PRUNE_INTERVAL {
+            buckets.prune(&self.rates, now);
+        }
+
+        let mut wait = Duration::ZERO;
+        for key in keys {
+            let bucket = buckets.by_key.entry((action, key.clone())).or_insert(Bucket { tokens: rate.burst as f64, updated: now });
+            bucket.refill(rate, now);
+            if bucket.tokens < 1.0 {
+                wait = wait.max(Duration::from_secs_f64((1.0 - bucket.tokens) / rate.per_second()));
+            }
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        for key in keys {
+            if let Some(bucket) = buckets.by_key.get_mut(&(action, key.clone())) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Buckets {
+    // A full bucket is the same as no bucket
+    fn prune(&mut self, rates: &HashMap<Action, Rate>, now: Instant) {
+        self.by_key.retain(|(action, _), bucket| match rates.get(action) {
+            Some(rate) => {
+                bucket.refill(*rate, now);
+                bucket.tokens < rate.burst as f64
+            }
+            None => false,
+        });
+        self.last_prune = now;
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_second()).min(rate.burst as f64);
+        self.updated = now;
+    }
+}
+
+/// Whole seconds to wait, rounded up so retrying right then succeeds.
+pub fn retry_after_secs(wait: Duration) -> u64 {
+    wait.as_secs_f64().ceil().max(1.0) as u64
+}
+
+/// The response for a refused HTTP request.
+pub fn too_many_requests(wait: Duration) -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after_secs(wait).to_string())],
+        "Too many requests, try again later",
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn limiter(burst: u32, per_minute: u32) -> RateLimiter {
+        RateLimiter::new(HashMap::from([(Action::Message, Rate { burst, per_minute })]))
+    }
+
+    fn user(id: i64) -> Key {
+        Key::User(id)
+    }
+
+    #[test]
+    fn allows_the_burst_then_refuses() {
+        let limiter = limiter(2, 60);
+        let now = Instant::now();
+        assert!(limiter.check_at(Action::Message, &[user(1)], now).is_ok());
+        assert!(limiter.check_at(Action::Message, &[user(1)], now).is_ok());
+        // One token a second, and the bucket is empty
+        assert_eq!(limiter.check_at(Action::Message, &[user(1)], now), Err(SECOND));
+        // Other keys have their own buckets
+        assert!(limiter.check_at(Action::Message, &[user(2)], now).is_ok());
+    }
+
+    #[test]
+    fn refills_at_the_configured_rate() {
+        let limiter = limiter(1, 60);
+        let now = Instant::now();
+        assert!(limiter.check_at(Action::Message, &[user(1)], now).is_ok());
+        let half = limiter.check_at(Action::Message, &[user(1)], now + SECOND / 2).unwrap_err();
+        assert!(half > SECOND / 2 - Duration::from_millis(1) && half <= SECOND / 2, "{:?}", half);
+        assert!(limiter.check_at(Action::Message, &[user(1)], now + SECOND).is_ok());
+        // Never fuller than the burst, however long it sat
+        let later = now + 60 * SECOND;
+        assert!(limiter.check_at(Action::Message, &[user(1)], later).is_ok());
+        assert!(limiter.check_at(Action::Message, &[user(1)], later).is_err());
+    }
+
+    #[test]
+    fn takes_nothing_when_any_key_is_empty() {
+        let limiter = limiter(1, 60);
+        let now = Instant::now();
+        let ip = Key::Ip(IpAddr::from([127, 0, 0, 1]));
+        assert!(limiter.check_at(Action::Message, std::slice::from_ref(&ip), now).is_ok());
+        assert!(limiter.check_at(Action::Message, &[user(1), ip], now).is_err());
+        // The refused attempt left the user's token in place
+        assert!(limiter.check_at(Action::Message, &[user(1)], now).is_ok());
+        assert!(limiter.check_at(Action::Message, &[user(1)], now).is_err());
+    }
+
+    #[test]
+    fn actions_without_a_rate_are_not_limited() {
+        let limiter = limiter(1, 60);
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert!(limiter.check_at(Action::Login, &[user(1)], now).is_ok());
+        }
+    }
+
+    #[test]
+    fn only_events_the_page_sends_itself_are_automatic() {
+        for action in [Action::Typing, Action::MarkRead, Action::Ping] {
+            assert!(action.is_automatic());
+        }
+        for action in [Action::Message, Action::EditMessage, Action::DeleteMessage, Action::Login, Action::RedeemInvite] {
+            assert!(!action.is_automatic());
+        }
+    }
+
+    #[test]
+    fn usernames_differing_in_case_share_a_bucket() {
+        let limiter = RateLimiter::new(HashMap::from([(Action::Login, Rate { burst: 2, per_minute: 1 })]));
+        let now = Instant::now();
+        assert!(limiter.check_at(Action::Login, &[Key::username("alice")], now).is_ok());
+        assert!(limiter.check_at(Action::Login, &[Key::username("ALICE")], now).is_ok());
+        assert!(limiter.check_at(Action::Login, &[Key::username("aLiCe")], now).is_err());
+        assert!(limiter.check_at(Action::Login, &[Key::username("bob")], now).is_ok());
+    }
+
+    #[test]
+    fn retry_after_rounds_up_to_whole_seconds() {
+        assert_eq!(retry_after_secs(Duration::ZERO), 1);
+        assert_eq!(retry_after_secs(Duration::from_millis(200)), 1);
+        assert_eq!(retry_after_secs(SECOND), 1);
+        assert_eq!(retry_after_secs(Duration::from_millis(1001)), 2);
+        assert_eq!(retry_after_secs(90 * SECOND), 90);
+    }
+
+    #[test]
+    fn prunes_buckets_that_have_refilled() {
+        let limiter = limiter(2, 1);
+        let now = Instant::now();
+        assert!(limiter.check_at(Action::Message, &[user(1)], now).is_ok());
+        assert!(limiter.check_at(Action::Message, &[user(2)], now + 30 * SECOND).is_ok());
+        assert!(limiter.check_at(Action::Message, &[user(2)], now + 30 * SECOND).is_ok());
+        assert_eq!(limiter.buckets.lock().unwrap().by_key.len(), 2);
+
+        // User 1 is back to full a minute after using one token, user 2 isn't
+        limiter.check_at(Action::Message, &[user(3)], now + PRUNE_INTERVAL).unwrap();
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(!buckets.by_key.contains_key(&(Action::Message, user(1))));
+        assert!(buckets.by_key.contains_key(&(Action::Message, user(2))));
+        assert!(buckets.by_key.contains_key(&(Action::Message, user(3))));
+    }
+}
@@ -1,26 +1,77 @@
 use askama::Template;
+use crate::database::SessionView;
 
 #[derive(Template)]
 #[template(path = "index.html")]
 pub struct IndexTemplate<'a> {
     pub username: &'a str,
-    pub chats: Vec<ChatView>, // (id, name)
+    pub csrf_token: &'a str,
+    pub chats: Vec<ChatView>,
 }
 
 pub struct ChatView {
     pub id: i64,
     pub name: String,
+    pub unread: i64,
 }
 
 #[derive(Template)]
 #[template(path = "chat.html")]
 pub struct ChatTemplate<'a> {
     pub username: &'a str,
-    pub messages: Vec<MessageView>, // (username, message)
-    pub chats: Vec<ChatView>,   // (id, name)
+    pub csrf_token: &'a str,
+    pub messages: Vec<MessageView>, // oldest first
+    pub older_cursor: Option<i64>,
+    pub synced_at: Option<String>,
+    pub can_invite: bool,
+    pub max_message_chars: usize,
+    pub chats: Vec<ChatView>,
 }
 
+impl ChatTemplate<'_> {
+    /// Whether the message at `index` is the first one of its (UTC) day.
+    pub fn starts_new_day(&self, index: &usize) -> bool {
+        let index = *index;
+        index == 0 || self.messages[index].day() != self.messages[index - 1].day()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "invite.html")]
+pub struct InviteTemplate<'a> {
+    pub username: &'a str,
+    pub csrf_token: &'a str,
+    pub chat_name: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "sessions.html")]
+pub struct SessionsTemplate<'a> {
+    pub username: &'a str,
+    pub csrf_token: &'a str,
+    pub current_session_id: i64,
+    pub sessions: Vec<SessionView>, // most recently used first
+}
+
+#[derive(serde::Serialize)]
 pub struct MessageView {
+    pub id: i64,
+    #[serde(rename = "author")]
     pub username: String,
     pub text: String,
+    pub sent_at: String, // ISO-8601, UTC
+    pub edited_at: Option<String>,
+    pub deleted: bool, // tombstone, `text` is empty
+}
+
+impl MessageView {
+    /// `YYYY-MM-DD` part of `sent_at`
+    pub fn day(&self) -> &str {
+        self.sent_at.get(..10).unwrap_or(&self.sent_at)
+    }
+
+    /// `HH:MM` part of `sent_at`
+    pub fn time(&self) -> &str {
+        self.sent_at.get(11..16).unwrap_or("")
+    }
 }
\ No newline at end of file
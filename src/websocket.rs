@@ -1,80 +1,204 @@
-use axum::extract::ws::{Message, WebSocket};
-use axum::extract::{WebSocketUpgrade, Path};
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
+use axum::extract::{WebSocketUpgrade, Path, Query};
+use axum::http::HeaderMap;
 use axum::response::IntoResponse;
 use axum::http::StatusCode;
 use uuid::Uuid;
 use futures_util::{stream::StreamExt, sink::SinkExt};
-use tokio::sync::mpsc;
-use crate::{AppState, SocketData};
-use crate::auth::AuthenticatedUser;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{interval_at, sleep_until, timeout, Instant, MissedTickBehavior};
+use crate::AppState;
+use crate::hub::SocketData;
+use crate::outbox::Outbox;
+use crate::auth::{is_allowed_origin, AuthenticatedUser, ClientIp};
+use crate::database::MessageCursor;
+use crate::template::MessageView;
+use crate::protocol::{negotiate_version, ClientEvent, ErrorCode, ServerEvent, CLOSE_HEARTBEAT_TIMEOUT, CLOSE_RATE_LIMITED, LEGACY_VERSION};
+use crate::ratelimit::{retry_after_secs, Action, Key};
+
+#[derive(serde::Deserialize)]
+pub struct SocketParams {
+    v: Option<u32>,
+    // Newest message the client already has, to replay what it missed
+    last_seen: Option<i64>,
+    // When the client was last in sync, to replay edits and deletes of messages it already has
+    since: Option<String>,
+}
+
+/// Where a reconnecting client left off.
+struct Resume {
+    last_seen: i64,
+    since: Option<String>,
+}
 
 pub async fn chatsocket_handler(
     ws: WebSocketUpgrade,
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(chat_id): Path<i64>,
-    user: AuthenticatedUser
+    Query(params): Query<SocketParams>,
+    headers: HeaderMap,
+    user: AuthenticatedUser,
+    ClientIp(ip): ClientIp
 ) -> impl IntoResponse {
-    if state.db_action().check_chat_membership(user.user_id, chat_id).unwrap_or(false) == false {
+    // The upgrade is a GET, so the CSRF token check doesn't cover it
+    if !is_allowed_origin(&headers, &state) {
+        return (StatusCode::FORBIDDEN, "Origin not allowed").into_response();
+    }
+    if state.hub.is_closed() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Server restarting").into_response();
+    }
+    if !state.db_action().check_chat_membership(user.user_id, chat_id).await.unwrap_or(false) {
         return (StatusCode::FORBIDDEN, "You are not a member of this chat").into_response();
     }
 
-    let username = user.username;
-    ws.on_upgrade(move |socket| handle_socket(socket, state, chat_id, username))
+    let version = match negotiate_version(params.v, state.config.allow_legacy_protocol) {
+        Ok(version) => version,
+        Err(reason) => return (StatusCode::BAD_REQUEST, reason).into_response(),
+    };
+    // Legacy clients have no message IDs to resume from
+    let resume = params
+        .last_seen
+        .filter(|_| version != LEGACY_VERSION)
+        .map(|last_seen| Resume { last_seen, since: params.since });
+    let max_frame_bytes = state.config.max_frame_bytes;
+    ws.max_message_size(max_frame_bytes)
+        .max_frame_size(max_frame_bytes)
+        .on_upgrade(move |socket| handle_socket(socket, state, chat_id, user, ip, version, resume))
 }
 
-async fn handle_socket(socket: WebSocket, state: AppState, chat_id: i64, username: String) {
+async fn handle_socket(socket: WebSocket, state: AppState, chat_id: i64, user: AuthenticatedUser, ip: IpAddr, version: u32, resume: Option<Resume>) {
+    // Lets shutdown wait for this task, and with it any message still being stored
+    let _guard = state.track_connection();
     let socket_id = Uuid::new_v4().to_string(); // Unique ID for each socket
-    let (tx, mut rx) = mpsc::unbounded_channel();
+    let outbox = Arc::new(Outbox::new(
+        state.config.outbound_queue_capacity,
+        state.config.slow_consumer_policy,
+        state.outbox_stats.clone(),
+    ));
     let (mut ws_sender, mut ws_receiver) = socket.split();
 
-    // Store the socket in the shared state
-    {
-        let mut sockets = state.sockets.lock().unwrap();
-        let socket_data = SocketData {
-            chat_id,
-            socket: tx,
-        };
-        sockets.insert(socket_id.clone(), socket_data);
+    // Register the socket in its chat, announcing the user if this is their first tab
+    let socket_data = SocketData {
+        chat_id,
+        user_id: user.user_id,
+        username: user.username.clone(),
+        session_id: user.session_id,
+        version,
+        socket: outbox.clone(),
+    };
+    if !state.hub.join(&socket_id, socket_data) {
+        // Shutdown already closed every registered socket, this one would be missed
+        return;
+    }
+    println!("New client connected with id: {} (protocol v{})", socket_id, version);
+
+    // Changes from here on arrive live; the client resumes from this time next
+    let server_time = match state.db_action().current_time().await {
+        Ok(now) => Some(now),
+        Err(e) => {
+            eprintln!("Failed to read the database time: {}", e);
+            None
+        }
+    };
+
+    // Broadcasts may already be queued since joining; the greeting and replay go
+    // ahead of them, before the writer starts sending anything
+    let mut greeting = vec![
+        ServerEvent::Hello { version, chat_id, server_time },
+        ServerEvent::Presence { chat_id, online: state.hub.online_users(chat_id) },
+    ];
+    if let Some(resume) = resume {
+        greeting.extend(replay(&state, chat_id, resume.last_seen, resume.since.as_deref()).await);
     }
-    println!("New client connected with id: {}", socket_id);
+    outbox.prepend(greeting.iter().filter_map(|event| event.encode(version)).map(Message::Text).collect());
 
     // Spawn a task to handle outgoing messages
     let socket_id_clone = socket_id.clone();
     let state_clone = state.clone();
-    tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            if ws_sender.send(msg).await.is_err() {
+    let outbox_clone = outbox.clone();
+    let mut send_task = tokio::spawn(async move {
+        while let Some(msg) = outbox_clone.pop().await {
+            // A close frame from the server (see Hub::close) ends the connection
+            let closing = matches!(msg, Message::Close(_));
+            if ws_sender.send(msg).await.is_err() || closing {
                 break;
             }
         }
         println!("Disconnecting a client with id: {}", socket_id_clone);
-        // Remove the socket from a HashMap when the connection is closed
-        let mut sockets = state_clone.sockets.lock().unwrap();
-        sockets.remove(&socket_id_clone);
+        outbox_clone.close();
+        // Remove the socket from its room when the connection is closed
+        state_clone.hub.leave(chat_id, &socket_id_clone);
     });
 
-    while let Some(Ok(msg)) = ws_receiver.next().await {
+    let mut conn = Connection {
+        state: state.clone(),
+        chat_id,
+        user_id: user.user_id,
+        ip,
+        username: user.username,
+        socket_id: socket_id.clone(),
+        version,
+        outbox: outbox.clone(),
+        typing_until: None,
+        rate_limited_events: 0,
+    };
+
+    let heartbeat_interval = state.config.heartbeat_interval;
+    let mut heartbeat = interval_at(Instant::now() + heartbeat_interval, heartbeat_interval);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // Pings sent since the client was last heard from
+    let mut missed_heartbeats = 0;
+
+    loop {
+        let typing_until = conn.typing_until;
+        let msg = tokio::select! {
+            msg = ws_receiver.next() => match msg {
+                Some(Ok(msg)) => msg,
+                _ => break,
+            },
+            // Ping the client, or give up on it if it stopped answering
+            _ = heartbeat.tick() => {
+                if missed_heartbeats >= state.config.heartbeat_missed_limit {
+                    println!("Client {} missed {} heartbeats, disconnecting", socket_id, missed_heartbeats);
+                    disconnect(&outbox, &mut send_task, CLOSE_HEARTBEAT_TIMEOUT, "Heartbeat timeout").await;
+                    break;
+                }
+                outbox.push(Message::Ping(Vec::new()));
+                missed_heartbeats += 1;
+                continue;
+            }
+            // The writer stopped, either because the client went away or we closed it
+            _ = &mut send_task => break,
+            // Too far behind under the disconnect policy
+            _ = outbox.overflowed() => {
+                println!("Client {} is too slow, disconnecting", socket_id);
+                finish_writer(&mut send_task).await;
+                break;
+            }
+            // The client went quiet without sending typing_stopped
+            _ = sleep_until(typing_until.unwrap_or_else(Instant::now)), if typing_until.is_some() => {
+                conn.stop_typing();
+                continue;
+            }
+        };
+        // Any frame shows the client is still there, not just a pong
+        missed_heartbeats = 0;
         match msg {
-            Message::Text(text) => {
-                match state.db_action().insert_message(&text, &username, chat_id) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        eprintln!("Failed to inserte a message: {}", e);
-                        continue
-                    }
+            Message::Text(frame) => {
+                let result = match parse_client_frame(frame, version) {
+                    Ok(event) => handle_client_event(&mut conn, event).await,
+                    Err(reason) => Err(ServerEvent::error(ErrorCode::BadRequest, reason)),
                 };
-
-                let broadcast_message = format!("{}: {}", username, text);
-
-                let sockets = state.sockets.lock().unwrap();
-                for (id, socket_data) in sockets.iter() {
-                    if id != &socket_id {
-                        if socket_data.chat_id != chat_id {
-                            continue; // Skip if chat IDs do not match
-                        }
-                        let sender = &socket_data.socket;
-                        let _ = sender.send(Message::Text(broadcast_message.clone()));
-                    }
+                if let Err(error) = result {
+                    send_event(&outbox, version, &error);
+                }
+                if conn.rate_limited_events >= state.config.rate_limit_disconnect_after {
+                    println!("Client {} ignored rate limits, disconnecting", socket_id);
+                    disconnect(&outbox, &mut send_task, CLOSE_RATE_LIMITED, "Rate limit exceeded").await;
+                    break;
                 }
             }
             Message::Close(_) => {
@@ -86,6 +210,262 @@ async fn handle_socket(socket: WebSocket, state: AppState, chat_id: i64, usernam
     }
 
     // Cleanup
-    let mut sockets = state.sockets.lock().unwrap();
-    sockets.remove(&socket_id);
-}
\ No newline at end of file
+    conn.stop_typing();
+    state.hub.leave(chat_id, &socket_id);
+    // Lets the writer finish what is queued and exit
+    outbox.close();
+    if outbox.dropped() > 0 {
+        println!("Client {} missed {} messages while too slow", socket_id, outbox.dropped());
+    }
+}
+
+/// How long a `typing_started` stays in effect without being repeated.
+const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+
+/// How long a client being disconnected (too slow, heartbeat timeout) gets to
+/// receive its close frame before the writer is dropped.
+const CLOSE_GRACE: Duration = Duration::from_secs(1);
+
+/// Closes the connection from the server side with `code`.
+async fn disconnect(outbox: &Outbox, send_task: &mut JoinHandle<()>, code: u16, reason: &str) {
+    outbox.push(Message::Close(Some(CloseFrame { code, reason: reason.to_string().into() })));
+    finish_writer(send_task).await;
+}
+
+/// Waits for the writer to deliver the close frame it was given, or aborts it
+/// if the client isn't reading.
+async fn finish_writer(send_task: &mut JoinHandle<()>) {
+    if timeout(CLOSE_GRACE, &mut *send_task).await.is_err() {
+        send_task.abort();
+    }
+}
+
+/// Per-socket context passed to the event handlers.
+struct Connection {
+    state: AppState,
+    chat_id: i64,
+    user_id: i64,
+    // Client address at upgrade time, for per-IP rate limits
+    ip: IpAddr,
+    username: String,
+    socket_id: String,
+    version: u32,
+    outbox: Arc<Outbox>,
+    // Set while this socket's user is typing, to the time the indicator expires
+    typing_until: Option<Instant>,
+    // Events refused by the rate limiter since the last one that went through
+    rate_limited_events: u32,
+}
+
+impl Connection {
+    fn start_typing(&mut self) {
+        // Only the first typing_started is relayed, repeats just push back the expiry
+        if self.typing_until.is_none() {
+            let event = ServerEvent::TypingStarted { chat_id: self.chat_id, user_id: self.user_id, username: self.username.clone() };
+            self.state.hub.broadcast(self.chat_id, Some(&self.socket_id), &event);
+        }
+        self.typing_until = Some(Instant::now() + TYPING_TIMEOUT);
+    }
+
+    fn stop_typing(&mut self) {
+        if self.typing_until.take().is_some() {
+            let event = ServerEvent::TypingStopped { chat_id: self.chat_id, user_id: self.user_id, username: self.username.clone() };
+            self.state.hub.broadcast(self.chat_id, Some(&self.socket_id), &event);
+        }
+    }
+}
+
+/// Applies one client event. An `Err` is an `error` frame for the sender.
+async fn handle_client_event(conn: &mut Connection, event: ClientEvent) -> Result<(), ServerEvent> {
+    let chat_id = conn.chat_id;
+    let action = rate_limit_action(&event);
+    if action.is_automatic() {
+        // Per user only: users sharing a NAT each have their own tabs pinging
+        if conn.state.rate_limiter.check(action, &[Key::User(conn.user_id)]).is_err() {
+            return Ok(());
+        }
+    } else {
+        // Limited per user, so opening more tabs doesn't buy more, and per IP, so
+        // neither does registering more accounts
+        let keys = [Key::User(conn.user_id), Key::Ip(conn.ip)];
+        if let Err(wait) = conn.state.rate_limiter.check(action, &keys) {
+            conn.rate_limited_events += 1;
+            let message = format!("Too many requests, retry in {} s", retry_after_secs(wait));
+            return Err(ServerEvent::error(ErrorCode::RateLimited, message));
+        }
+        conn.rate_limited_events = 0;
+    }
+    match event {
+        ClientEvent::Message { text } => {
+            check_text(conn, &text)?;
+            // Sending a message ends the typing indicator
+            conn.stop_typing();
+
+            let (id, sent_at) = conn.state.db_action().insert_message(&text, &conn.username, chat_id).await.map_err(|e| {
+                eprintln!("Failed to insert a message: {}", e);
+                ServerEvent::error(ErrorCode::Internal, "Failed to store message")
+            })?;
+
+            let event = ServerEvent::Message {
+                id,
+                chat_id,
+                author: conn.username.clone(),
+                text,
+                sent_at,
+            };
+            // Structured clients get their own message echoed back so they learn
+            // its ID; legacy clients render it locally and would show it twice
+            let skip = if conn.version == LEGACY_VERSION { Some(conn.socket_id.as_str()) } else { None };
+            conn.state.hub.broadcast(chat_id, skip, &event);
+
+            // Your own messages count as read, no receipt needed for that
+            if let Err(e) = conn.state.db_action().mark_read(conn.user_id, chat_id, id).await {
+                eprintln!("Failed to advance read marker: {}", e);
+            }
+        }
+        ClientEvent::EditMessage { id, text } => {
+            check_text(conn, &text)?;
+            check_own_message(conn, id).await?;
+
+            let edited_at = conn.state.db_action().edit_message(id, &conn.username, &text).await.map_err(|e| {
+                eprintln!("Failed to edit a message: {}", e);
+                ServerEvent::error(ErrorCode::Internal, "Failed to edit message")
+            })?;
+            match edited_at {
+                Some(edited_at) => conn.state.hub.broadcast(chat_id, None, &ServerEvent::MessageEdited { id, chat_id, text, edited_at }),
+                None => return Err(ServerEvent::error(ErrorCode::NotFound, "Message not found")),
+            }
+        }
+        ClientEvent::DeleteMessage { id } => {
+            check_own_message(conn, id).await?;
+
+            let deleted = conn.state.db_action().delete_message(id, &conn.username).await.map_err(|e| {
+                eprintln!("Failed to delete a message: {}", e);
+                ServerEvent::error(ErrorCode::Internal, "Failed to delete message")
+            })?;
+            if !deleted {
+                return Err(ServerEvent::error(ErrorCode::NotFound, "Message not found"));
+            }
+            conn.state.hub.broadcast(chat_id, None, &ServerEvent::MessageDeleted { id, chat_id });
+        }
+        ClientEvent::TypingStarted => conn.start_typing(),
+        ClientEvent::TypingStopped => conn.stop_typing(),
+        ClientEvent::Ping => send_event(&conn.outbox, conn.version, &ServerEvent::Pong),
+        ClientEvent::MarkRead { message_id } => {
+            mark_read(&conn.state, chat_id, conn.user_id, &conn.username, message_id).await.map_err(|e| {
+                eprintln!("Failed to advance read marker: {}", e);
+                ServerEvent::error(ErrorCode::Internal, "Failed to mark messages as read")
+            })?;
+        }
+    }
+    Ok(())
+}
+
+fn rate_limit_action(event: &ClientEvent) -> Action {
+    match event {
+        ClientEvent::Message { .. } => Action::Message,
+        ClientEvent::EditMessage { .. } => Action::EditMessage,
+        ClientEvent::DeleteMessage { .. } => Action::DeleteMessage,
+        ClientEvent::TypingStarted | ClientEvent::TypingStopped => Action::Typing,
+        ClientEvent::MarkRead { .. } => Action::MarkRead,
+        ClientEvent::Ping => Action::Ping,
+    }
+}
+
+/// Advances a member's read marker and, if it moved, tells the chat with a `read_receipt`.
+pub async fn mark_read(state: &AppState, chat_id: i64, user_id: i64, username: &str, message_id: i64) -> Result<(), sqlite::Error> {
+    if let Some(message_id) = state.db_action().mark_read(user_id, chat_id, message_id).await? {
+        let event = ServerEvent::ReadReceipt { chat_id, user_id, username: username.to_string(), message_id };
+        state.hub.broadcast(chat_id, None, &event);
+    }
+    Ok(())
+}
+
+/// Events bringing a client that has seen up to `last_seen`, and was in sync at
+/// `since`, up to date, ending with `resumed`.
+async fn replay(state: &AppState, chat_id: i64, last_seen: i64, since: Option<&str>) -> Vec<ServerEvent> {
+    let limit = state.config.resume_max_messages;
+    let incomplete = ServerEvent::Resumed { chat_id, last_message_id: last_seen, complete: false };
+    // One more than the limit tells whether there are too many
+    let changed = match since {
+        Some(since) => match state.db_action().get_changed_messages(chat_id, last_seen, since, limit + 1).await {
+            Ok(changed) => changed,
+            Err(e) => {
+                eprintln!("Failed to load changed messages to replay: {}", e);
+                return vec![incomplete];
+            }
+        },
+        None => Vec::new(),
+    };
+    let remaining = limit - changed.len() as i64;
+    if remaining < 0 {
+        return vec![incomplete];
+    }
+    let messages = match state.db_action().get_messages(chat_id, MessageCursor::After(last_seen), remaining + 1).await {
+        Ok(messages) if messages.len() as i64 <= remaining => messages,
+        Ok(_) => return vec![incomplete],
+        Err(e) => {
+            eprintln!("Failed to load messages to replay: {}", e);
+            return vec![incomplete];
+        }
+    };
+
+    let last_message_id = messages.last().map_or(last_seen, |message| message.id);
+    let mut events = Vec::new();
+    for message in changed {
+        if message.deleted {
+            events.push(ServerEvent::MessageDeleted { id: message.id, chat_id });
+        } else if let Some(edited_at) = message.edited_at {
+            events.push(ServerEvent::MessageEdited { id: message.id, chat_id, text: message.text, edited_at });
+        }
+    }
+    for message in messages {
+        let MessageView { id, username, text, sent_at, edited_at, deleted } = message;
+        events.push(ServerEvent::Message { id, chat_id, author: username, text: text.clone(), sent_at });
+        if deleted {
+            events.push(ServerEvent::MessageDeleted { id, chat_id });
+        } else if let Some(edited_at) = edited_at {
+            events.push(ServerEvent::MessageEdited { id, chat_id, text, edited_at });
+        }
+    }
+    events.push(ServerEvent::Resumed { chat_id, last_message_id, complete: true });
+    events
+}
+
+/// Rejects message text that is blank or longer than the configured limit.
+fn check_text(conn: &Connection, text: &str) -> Result<(), ServerEvent> {
+    if text.trim().is_empty() {
+        return Err(ServerEvent::error(ErrorCode::BadRequest, "Message text must not be empty"));
+    }
+    let max_chars = conn.state.config.max_message_chars;
+    if text.chars().count() > max_chars {
+        return Err(ServerEvent::error(ErrorCode::BadRequest, format!("Message text must be at most {} characters", max_chars)));
+    }
+    Ok(())
+}
+
+/// Checks that message `id` exists in this chat, is not deleted and was written by this user.
+async fn check_own_message(conn: &Connection, id: i64) -> Result<(), ServerEvent> {
+    match conn.state.db_action().get_message_author(id, conn.chat_id).await {
+        Ok(Some((author, false))) if author == conn.username => Ok(()),
+        Ok(Some((_, false))) => Err(ServerEvent::error(ErrorCode::Forbidden, "You can only change your own messages")),
+        Ok(_) => Err(ServerEvent::error(ErrorCode::NotFound, "Message not found")),
+        Err(e) => {
+            eprintln!("Failed to look up a message: {}", e);
+            Err(ServerEvent::error(ErrorCode::Internal, "Failed to look up message"))
+        }
+    }
+}
+
+fn parse_client_frame(frame: String, version: u32) -> Result<ClientEvent, String> {
+    if version == LEGACY_VERSION {
+        return Ok(ClientEvent::Message { text: frame });
+    }
+    serde_json::from_str(&frame).map_err(|e| format!("Invalid frame: {}", e))
+}
+
+fn send_event(outbox: &Outbox, version: u32, event: &ServerEvent) {
+    if let Some(frame) = event.encode(version) {
+        outbox.push(Message::Text(frame));
+    }
+}
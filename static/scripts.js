@@ -1,9 +1,19 @@
 let input = document.getElementById("chat-input");
 let chatBox = document.getElementById("chat");
 let socket;
+let leaving = false;
 let username_field = document.getElementById("username");
 let user_count_field = document.getElementById("user-count");
 
+// Chat socket protocol version, see src/protocol.rs
+const PROTOCOL_VERSION = 1;
+// Application-level keepalive, for proxies that drop WebSocket ping frames.
+// Must be shorter than the server's heartbeat_interval_secs.
+const KEEPALIVE_MS = 20000;
+let keepalive;
+// Sent with every state-changing request, see src/auth.rs
+const CSRF_TOKEN = document.querySelector('meta[name="csrf-token"]').content;
+
 function getChatIdFromPath() {
     const m = window.location.pathname.match(/^\/chat\/(\d+)\/?$/);
     return m ? parseInt(m[1], 10) : null;
@@ -14,38 +24,383 @@ window.onload = function () {
     if (!chatId) {
         return;
     }
-    let socketUrl = `wss://chat.def1de.com/chatsocket/${chatId}`;
+    localizeTimestamps(chatBox);
+    loadReadMarkers(chatId);
+    chatBox.addEventListener("scroll", () => {
+        if (chatBox.scrollTop === 0) {
+            loadOlderMessages(chatId);
+        }
+    });
+    connect(chatId);
+};
+
+// Reconnect delays double from RECONNECT_BASE_MS up to RECONNECT_MAX_MS, with
+// jitter so a restarted server isn't hit by every client at once
+const RECONNECT_BASE_MS = 1000;
+const RECONNECT_MAX_MS = 30000;
+let reconnectAttempts = 0;
+// A refused handshake (session gone, no longer a member, bad request) looks like
+// any other drop. After PROBE_AFTER_FAILURES in a row without the socket opening,
+// each retry first asks over HTTP whether we may still connect, and after
+// MAX_FAILED_HANDSHAKES we stop
+const PROBE_AFTER_FAILURES = 3;
+const MAX_FAILED_HANDSHAKES = 10;
+let failedHandshakes = 0;
+let connection_status_field = document.getElementById("connection-status");
+// Server time from which we have every edit and delete: the page render, then
+// the hello of the last connection that resumed completely
+let syncedAt = chatBox ? chatBox.dataset.syncedAt : undefined;
+let pendingSyncedAt;
+
+// Opens the chat socket. The server replays anything newer than the newest
+// rendered message, and edits and deletes since syncedAt, so nothing is missed
+// between page load or a drop and now
+function connect(chatId) {
+    let socketUrl = `wss://chat.def1de.com/chatsocket/${chatId}?v=${PROTOCOL_VERSION}&last_seen=${newestMessageId()}`;
+    if (syncedAt) {
+        socketUrl += `&since=${encodeURIComponent(syncedAt)}`;
+    }
     socket = new WebSocket(socketUrl);
+    let opened = false;
     socket.onopen = function () {
-        updateUserCount();
+        opened = true;
+        failedHandshakes = 0;
         scrollToBottom();
+        markRead();
+        keepalive = setInterval(() => socket.send(JSON.stringify({ type: "ping" })), KEEPALIVE_MS);
     };
 
-    socket.onclose = function () {
-        alert("Connection closed. Please refresh the page to reconnect.");
+    socket.onclose = function (event) {
+        clearInterval(keepalive);
+        // Whoever was typing will have stopped by the time we are back
+        typing.clear();
+        renderTyping();
+        if (leaving) {
+            return;
+        }
+        // Membership ended or chat deleted, see src/protocol.rs
+        if (event.code === 4001 || event.code === 4002) {
+            alert(event.reason);
+            window.location.href = "/";
+            return;
+        }
+        // Session revoked or logged out elsewhere
+        if (event.code === 4006) {
+            alert(event.reason);
+            window.location.href = "/auth";
+            return;
+        }
+        if (!opened && ++failedHandshakes >= PROBE_AFTER_FAILURES) {
+            checkAccess(chatId);
+            return;
+        }
+        // Anything else (network drop, server restart, too slow, missed heartbeats) is retried
+        scheduleReconnect(chatId);
     };
 
     socket.onmessage = (event) => {
-        const parts = event.data.split(": ", 2);
-        chatBox.innerHTML +=
-            `<div class="message left">
-                    <p class="username">` +
-            parts[0] +
-            `</p>
-                    <p class="message_content">` +
-            parts[1] +
-            `</p>
-                </div>`;
-        scrollToBottom();
+        let data;
+        try {
+            data = JSON.parse(event.data);
+        } catch (e) {
+            console.error("Malformed frame from server:", event.data);
+            return;
+        }
+        handleServerEvent(data);
     };
-};
+}
+
+function scheduleReconnect(chatId) {
+    const delay = Math.min(RECONNECT_MAX_MS, RECONNECT_BASE_MS * 2 ** reconnectAttempts);
+    reconnectAttempts++;
+    setConnectionStatus("Connection lost, reconnecting…");
+    setTimeout(() => connect(chatId), delay / 2 + Math.random() * (delay / 2));
+}
 
-document.getElementById("inviteBtn").onclick = function () {
+// Decides what to do after repeated failed handshakes, using an endpoint that
+// needs the same session and membership as the socket
+function checkAccess(chatId) {
+    fetch(`/api/chats/${chatId}/online`, { credentials: "same-origin" })
+        .then((response) => {
+            // The session expired or was revoked
+            if (response.redirected && new URL(response.url).pathname === "/auth") {
+                window.location.href = "/auth";
+            } else if (response.status === 403 || response.status === 404) {
+                alert("You are no longer a member of this chat.");
+                window.location.href = "/";
+            } else if (failedHandshakes >= MAX_FAILED_HANDSHAKES) {
+                setConnectionStatus("Can't connect to the chat, reload the page to try again");
+            } else {
+                // Still allowed in, so the server or the network is at fault
+                scheduleReconnect(chatId);
+            }
+        })
+        .catch(() => scheduleReconnect(chatId));
+}
+
+function setConnectionStatus(text) {
+    connection_status_field.textContent = text;
+    connection_status_field.hidden = text === "";
+}
+
+function handleServerEvent(data) {
+    switch (data.type) {
+        case "hello":
+            if (data.version !== PROTOCOL_VERSION) {
+                console.warn(`Server speaks protocol v${data.version}, expected v${PROTOCOL_VERSION}`);
+            }
+            pendingSyncedAt = data.server_time;
+            break;
+        case "presence":
+            online.clear();
+            data.online.forEach((u) => online.set(u.user_id, u.username));
+            renderOnline();
+            break;
+        case "resumed":
+            // Too much was missed to replay, start over from the latest history
+            if (!data.complete) {
+                window.location.reload();
+                return;
+            }
+            if (pendingSyncedAt) {
+                syncedAt = pendingSyncedAt;
+            }
+            reconnectAttempts = 0;
+            setConnectionStatus("");
+            markRead();
+            renderReceipts();
+            break;
+        case "presence_joined":
+            online.set(data.user_id, data.username);
+            renderOnline();
+            break;
+        case "presence_left":
+            online.delete(data.user_id);
+            typing.delete(data.user_id);
+            renderOnline();
+            renderTyping();
+            break;
+        case "read_receipt":
+            if (data.username === username_field.innerText) {
+                lastMarkedRead = Math.max(lastMarkedRead, data.message_id);
+            }
+            readMarkers.set(data.user_id, { username: data.username, message_id: data.message_id });
+            renderReceipts();
+            break;
+        case "typing_started":
+            typing.set(data.user_id, data.username);
+            renderTyping();
+            break;
+        case "typing_stopped":
+            typing.delete(data.user_id);
+            renderTyping();
+            break;
+        case "message":
+            appendMessage(data);
+            markRead();
+            renderReceipts();
+            break;
+        case "message_edited":
+            markEdited(data);
+            break;
+        case "message_deleted":
+            markDeleted(data.id);
+            break;
+        case "pong":
+            break;
+        case "error":
+            console.error(`Server error (${data.code}): ${data.message}`);
+            if (data.code === "rate_limited") {
+                setConnectionStatus(data.message);
+                setTimeout(() => setConnectionStatus(""), 3000);
+            }
+            break;
+        default:
+            console.warn("Unknown event type:", data.type);
+    }
+}
+
+function appendMessage(msg) {
+    if (chatBox.querySelector(`.message[data-id="${msg.id}"]`)) {
+        return; // already rendered
+    }
+    const day = msg.sent_at.slice(0, 10);
+    const separators = chatBox.querySelectorAll(".day-separator");
+    const lastDay = separators.length ? separators[separators.length - 1].dataset.day : null;
+    if (day !== lastDay) {
+        chatBox.appendChild(buildDaySeparator(day));
+    }
+    chatBox.appendChild(buildMessage(msg));
+    scrollToBottom();
+}
+
+function buildMessage(msg) {
+    const own = msg.author === username_field.innerText;
+    const wrapper = document.createElement("div");
+    wrapper.className = own ? "message right" : "message left";
+    wrapper.dataset.id = String(msg.id);
+    const name = document.createElement("p");
+    name.className = "username";
+    name.textContent = own ? "You" : msg.author;
+    const content = document.createElement("p");
+    content.className = "message_content";
+    content.textContent = msg.text;
+    const meta = document.createElement("p");
+    meta.className = "meta";
+    const time = document.createElement("time");
+    time.className = "timestamp";
+    time.dateTime = msg.sent_at;
+    time.textContent = formatTime(msg.sent_at);
+    meta.append(time);
+    wrapper.append(name, content, meta);
+    if (msg.edited_at) {
+        setEditedMarker(wrapper, msg.edited_at);
+    }
+    if (msg.deleted) {
+        setDeleted(wrapper);
+    }
+    return wrapper;
+}
+
+function setEditedMarker(wrapper, editedAt) {
+    let marker = wrapper.querySelector(".edited");
+    if (!marker) {
+        marker = document.createElement("span");
+        marker.className = "edited";
+        marker.textContent = "(edited) ";
+        wrapper.querySelector(".meta").prepend(marker);
+    }
+    marker.title = editedAt;
+}
+
+function setDeleted(wrapper) {
+    wrapper.classList.add("deleted");
+    wrapper.querySelector(".message_content").textContent = "message deleted";
+    wrapper.querySelector(".edited")?.remove();
+}
+
+function markEdited(data) {
+    const wrapper = chatBox.querySelector(`.message[data-id="${data.id}"]`);
+    if (!wrapper) {
+        return;
+    }
+    wrapper.querySelector(".message_content").textContent = data.text;
+    setEditedMarker(wrapper, data.edited_at);
+}
+
+function markDeleted(id) {
+    const wrapper = chatBox.querySelector(`.message[data-id="${id}"]`);
+    if (wrapper) {
+        setDeleted(wrapper);
+    }
+}
+
+// Double-click one of your own messages to edit it, or clear the text to delete it
+chatBox.addEventListener("dblclick", (event) => {
+    const wrapper = event.target.closest(".message.right");
+    if (!wrapper || wrapper.classList.contains("deleted") || !socket) {
+        return;
+    }
+    const id = parseInt(wrapper.dataset.id, 10);
+    const current = wrapper.querySelector(".message_content").textContent;
+    const text = prompt("Edit message (leave empty to delete):", current);
+    if (text === null || text === current) {
+        return;
+    }
+    if (text.trim() === "") {
+        if (confirm("Delete this message?")) {
+            socket.send(JSON.stringify({ type: "delete_message", id }));
+        }
+        return;
+    }
+    socket.send(JSON.stringify({ type: "edit_message", id, text }));
+});
+
+function buildDaySeparator(day) {
+    const separator = document.createElement("div");
+    separator.className = "day-separator";
+    separator.dataset.day = day;
+    separator.textContent = formatDay(day);
+    return separator;
+}
+
+// Prepends a page of older messages, keeping the viewport where it was
+let loadingOlder = false;
+function loadOlderMessages(chatId) {
+    const cursor = chatBox.dataset.olderCursor;
+    if (!cursor || loadingOlder) {
+        return;
+    }
+    loadingOlder = true;
+    fetch(`/api/chats/${chatId}/messages?before=${cursor}`, { credentials: "same-origin" })
+        .then((response) => response.json())
+        .then((page) => {
+            const previousHeight = chatBox.scrollHeight;
+            const fragment = document.createDocumentFragment();
+            page.messages.forEach((msg) => {
+                if (!chatBox.querySelector(`.message[data-id="${msg.id}"]`)) {
+                    fragment.appendChild(buildMessage(msg));
+                }
+            });
+            chatBox.prepend(fragment);
+            rebuildDaySeparators();
+            chatBox.scrollTop = chatBox.scrollHeight - previousHeight;
+            if (page.older_cursor) {
+                chatBox.dataset.olderCursor = page.older_cursor;
+            } else {
+                delete chatBox.dataset.olderCursor;
+            }
+        })
+        .catch((error) => console.error("Error loading older messages:", error))
+        .finally(() => {
+            loadingOlder = false;
+        });
+}
+
+function rebuildDaySeparators() {
+    chatBox.querySelectorAll(".day-separator").forEach((el) => el.remove());
+    let lastDay = null;
+    chatBox.querySelectorAll(".message").forEach((el) => {
+        const day = el.querySelector("time.timestamp").dateTime.slice(0, 10);
+        if (day !== lastDay) {
+            el.before(buildDaySeparator(day));
+            lastDay = day;
+        }
+    });
+}
+
+function formatTime(iso) {
+    return new Date(iso).toLocaleTimeString([], { hour: "2-digit", minute: "2-digit" });
+}
+
+// Messages are grouped by UTC day, so label the separators in UTC as well
+function formatDay(day) {
+    return new Date(day + "T00:00:00Z").toLocaleDateString([], {
+        year: "numeric",
+        month: "long",
+        day: "numeric",
+        timeZone: "UTC",
+    });
+}
+
+// Server renders times in UTC, show them in the browser's timezone instead
+function localizeTimestamps(root) {
+    root.querySelectorAll("time.timestamp").forEach((el) => {
+        el.textContent = formatTime(el.dateTime);
+    });
+    root.querySelectorAll(".day-separator").forEach((el) => {
+        el.textContent = formatDay(el.dataset.day);
+    });
+}
+
+// Only rendered for chat owners and admins
+let inviteBtn = document.getElementById("inviteBtn");
+if (inviteBtn) inviteBtn.onclick = function () {
     let chatId = getChatIdFromPath();
     if (!chatId) {
         return;
     }
-    fetch(`/create_invite/${chatId}`, { method: "POST" })
+    fetch(`/create_invite/${chatId}`, { method: "POST", headers: { "X-CSRF-Token": CSRF_TOKEN } })
         .then((response) => response.json())
         .then((data) => {
             if (data.code) {
@@ -68,24 +423,43 @@ document.getElementById("inviteBtn").onclick = function () {
         });
 };
 
+document.getElementById("leaveBtn").onclick = function () {
+    let chatId = getChatIdFromPath();
+    if (!chatId || !confirm("Leave this chat?")) {
+        return;
+    }
+    leaving = true;
+    fetch(`/api/chats/${chatId}/leave`, { method: "POST", credentials: "same-origin", headers: { "X-CSRF-Token": CSRF_TOKEN } }).then((response) => {
+        if (response.ok) {
+            window.location.href = "/";
+        } else {
+            leaving = false;
+            response.text().then((text) => alert(text || "Failed to leave chat."));
+        }
+    });
+};
+
+input.addEventListener("input", function () {
+    if (input.value.trim() === "") {
+        stopTyping();
+    } else {
+        noteTyping();
+    }
+});
+
 input.addEventListener("keydown", function (event) {
     if (event.key === "Enter") {
         event.preventDefault();
         const message = input.value;
-        if (message.trim() !== "") {
-            socket.send(message);
-
-            input.value = "";
+        if (message.trim() === "" || !socket || socket.readyState !== WebSocket.OPEN) {
+            return;
         }
-
-        chatBox.innerHTML +=
-            `<div class="message right">
-                    <p class="username">You</p>
-                    <p class="message_content">` +
-            message +
-            `</p>
-                </div>`;
-        scrollToBottom();
+        // Rendered when the server echoes it back with its ID and timestamp.
+        // The server clears our typing indicator when a message arrives
+        socket.send(JSON.stringify({ type: "message", text: message }));
+        input.value = "";
+        clearTimeout(typingIdleTimer);
+        lastTypingSent = 0;
     }
 });
 
@@ -96,19 +470,149 @@ function scrollToBottom() {
     });
 }
 
-function updateUserCount() {
-    fetch("https://chat.def1de.com/status")
+// Members of this chat with the page open, user_id -> username
+const online = new Map();
+
+// Tell the server we've read up to the newest rendered message, while the tab is
+// visible. Sent at most once per MARK_READ_DELAY_MS, since a busy chat would
+// otherwise send one per message; the server drops mark_read past its rate limit,
+// so the marker only counts as moved once our own read_receipt comes back
+const MARK_READ_DELAY_MS = 1000;
+const MARK_READ_RETRY_MS = 5000;
+let lastMarkedRead = 0;
+let markReadTimer = null;
+let markReadRetried = 0;
+function markRead() {
+    if (markReadTimer === null) {
+        markReadTimer = setTimeout(sendMarkRead, MARK_READ_DELAY_MS);
+    }
+    // This chat's badge in the carousel is stale once we are looking at it
+    const chatId = getChatIdFromPath();
+    document
+        .querySelectorAll(`#chatCarousel a[href="/chat/${chatId}"] .unread`)
+        .forEach((badge) => badge.remove());
+}
+
+function sendMarkRead() {
+    markReadTimer = null;
+    if (document.visibilityState !== "visible" || !socket || socket.readyState !== WebSocket.OPEN) {
+        return;
+    }
+    const newest = newestMessageId();
+    if (newest <= lastMarkedRead) {
+        return;
+    }
+    socket.send(JSON.stringify({ type: "mark_read", message_id: newest }));
+    // Once per message ID: the marker may already be past it, and then no receipt comes
+    if (markReadRetried !== newest) {
+        markReadRetried = newest;
+        setTimeout(() => {
+            if (lastMarkedRead < newest) markRead();
+        }, MARK_READ_RETRY_MS);
+    }
+}
+
+function newestMessageId() {
+    const messages = chatBox.querySelectorAll(".message[data-id]");
+    return messages.length ? parseInt(messages[messages.length - 1].dataset.id, 10) : 0;
+}
+
+document.addEventListener("visibilitychange", markRead);
+
+// Other members' read markers, user_id -> { username, message_id }
+const readMarkers = new Map();
+
+function loadReadMarkers(chatId) {
+    fetch(`/api/chats/${chatId}/members`, { credentials: "same-origin" })
         .then((response) => response.json())
-        .then((data) => {
-            user_count_field.innerText = "Current users: " + data.connected_clients;
+        .then((members) => {
+            members.forEach((m) => {
+                if (m.username !== username_field.innerText) {
+                    readMarkers.set(m.user_id, { username: m.username, message_id: m.last_read_message_id });
+                } else {
+                    lastMarkedRead = Math.max(lastMarkedRead, m.last_read_message_id);
+                }
+            });
+            renderReceipts();
         })
-        .catch((error) => {
-            console.error("Error fetching status:", error);
-            user_count_field.innerText = "Current users: 0";
-        });
+        .catch((error) => console.error("Error loading read markers:", error));
+}
+
+// "Seen by" line under the newest message
+function renderReceipts() {
+    chatBox.querySelectorAll(".receipts").forEach((el) => el.remove());
+    const messages = chatBox.querySelectorAll(".message[data-id]");
+    if (!messages.length) {
+        return;
+    }
+    const newest = messages[messages.length - 1];
+    const id = parseInt(newest.dataset.id, 10);
+    const names = Array.from(readMarkers.values())
+        .filter((m) => m.message_id >= id && m.username !== username_field.innerText)
+        .map((m) => m.username)
+        .sort();
+    if (names.length) {
+        const receipts = document.createElement("p");
+        receipts.className = "receipts";
+        receipts.textContent = "Seen by " + names.join(", ");
+        newest.appendChild(receipts);
+    }
+}
+
+// Other members currently typing, user_id -> username
+const typing = new Map();
+let typing_field = document.getElementById("typing-indicator");
+
+function renderTyping() {
+    const names = Array.from(typing.values()).sort();
+    if (names.length === 0) {
+        typing_field.textContent = "";
+    } else if (names.length === 1) {
+        typing_field.textContent = `${names[0]} is typing…`;
+    } else if (names.length <= 3) {
+        typing_field.textContent = `${names.join(", ")} are typing…`;
+    } else {
+        typing_field.textContent = "Several people are typing…";
+    }
 }
 
-setInterval(updateUserCount, 10000);
+// typing_started is re-sent at most this often while the user keeps typing,
+// the server expires it after a few seconds without one
+const TYPING_REPEAT_MS = 3000;
+const TYPING_IDLE_MS = 4000;
+let lastTypingSent = 0;
+let typingIdleTimer = null;
+
+function sendTyping(started) {
+    if (!socket || socket.readyState !== WebSocket.OPEN) {
+        return;
+    }
+    socket.send(JSON.stringify({ type: started ? "typing_started" : "typing_stopped" }));
+}
+
+function noteTyping() {
+    const now = Date.now();
+    if (now - lastTypingSent > TYPING_REPEAT_MS) {
+        sendTyping(true);
+        lastTypingSent = now;
+    }
+    clearTimeout(typingIdleTimer);
+    typingIdleTimer = setTimeout(stopTyping, TYPING_IDLE_MS);
+}
+
+function stopTyping() {
+    clearTimeout(typingIdleTimer);
+    if (lastTypingSent !== 0) {
+        sendTyping(false);
+        lastTypingSent = 0;
+    }
+}
+
+function renderOnline() {
+    const names = Array.from(online.values()).sort();
+    user_count_field.innerText = "Online: " + names.length;
+    user_count_field.title = names.join(", ");
+}
 
 (() => {
     const root = document.getElementById("chatCarousel");